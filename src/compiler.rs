@@ -1,41 +1,247 @@
 
 
-use crate::syntax::{Expr, BinOp, Var, Stmt, Block, Program};
+use crate::syntax::{Expr, BinOp, Builtin, Var, Stmt, PrintItem, Block, Proc, Const, Record, Program, Span};
 use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufRead, Write};
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, ToPrimitive, Signed, Zero};
 
 
 #[derive(Debug, Clone, Copy)]
 pub enum Insn {
     Halt,
+    HaltWith, // halt, using the value on top of the stack as the exit status
+    Assert(Span), // fail if the value on top of the stack is zero
     Literal(i64),
+    LiteralFloat(f64),
     Add,
     Sub,
     Mul,
+    Div,
+    Mod,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr, // arithmetic: the sign bit is copied in
+    Neg,
+    Abs,
+    Min,
+    Max,
+    Pow,
+    IntToFloat,
+    FloatToInt, // truncates towards zero
     Lt,
     Gt,
-    Print,
+    Eq,
+    Ne,
+    Le,
+    Ge,
+    Print, // print a value, then a newline
+    Write, // print a value, without a newline
+    WriteStr(usize), // print a string from the constant table
+    Newline,
     Enter(usize),
     Exit(usize),
     GetLocal(usize),
     SetLocal(usize),
     Input,
+    Argc, // the number of inputs
+    Read, // the next int from the input stream, or 0 at its end
+    Eof, // whether a 'Read' has reached the end of the input stream
     Branch(isize),
     BranchZero(isize),
+    Switch(usize), // branch through a jump table, indexed by the value on top of the stack
+    Call(usize),
+    Ret,
+    Pop,
+    ArrayNew, // the value to fill the array with is on top of its length
+    ArrayGet,
+    ArraySet,
+    ArrayLen,
+    RecordNew(usize), // allocate a record with the given layout, with every field 0
+    GetField(usize), // the field is named by a string from the constant table
+    SetField(usize), // the value to store is on top of the record
+}
+
+#[derive(Debug)]
+pub enum CompileError {
+    UndeclaredVariable(Var),
+    AssignToConstant(Var),
+    DuplicateConstant(Var),
+    NotConstant(Var),
+    // Evaluating the initializer of a constant would fail at runtime.
+    ConstantFault(Var, RuntimeError),
+    UndefinedProcedure(Var),
+    DuplicateProcedure(Var),
+    // A procedure has the name of a builtin function.
+    BuiltinProcedure(Var),
+    DuplicateParameter(Var, Var),
+    ArityMismatch { name: Var, expected: usize, found: usize },
+    ReturnOutsideProcedure,
+    BadStep,
+    BreakOutsideLoop,
+    ContinueOutsideLoop,
+    UndeclaredRecord(Var),
+    DuplicateRecord(Var),
+    DuplicateField(Var, Var),
+    MissingField(Var, Var),
+    NoSuchField(Var, Var),
+    // No record has a field with this name.
+    UnknownField(Var),
+    DuplicateCase(i64),
+    DuplicateDefault,
+    MissingDefault,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::UndeclaredVariable(x) => write!(f, "variable '{}' is not declared", x),
+            CompileError::AssignToConstant(x) => write!(f, "cannot assign to constant '{}'", x),
+            CompileError::DuplicateConstant(x) => write!(f, "constant '{}' is defined more than once", x),
+            CompileError::NotConstant(x) => {
+                write!(f, "the initializer of constant '{}' is not a constant expression", x)
+            },
+            CompileError::ConstantFault(x, err) => write!(f, "cannot evaluate constant '{}': {}", x, err),
+            CompileError::UndefinedProcedure(p) => write!(f, "call to undefined procedure '{}'", p),
+            CompileError::DuplicateProcedure(p) => write!(f, "procedure '{}' is defined more than once", p),
+            CompileError::BuiltinProcedure(p) => write!(f, "'{}' is a builtin function, and cannot be redefined", p),
+            CompileError::DuplicateParameter(p, x) => {
+                write!(f, "procedure '{}' has more than one parameter named '{}'", p, x)
+            },
+            CompileError::ArityMismatch { name, expected, found } => {
+                write!(f, "'{}' expects {} argument(s) but was given {}", name, expected, found)
+            },
+            CompileError::ReturnOutsideProcedure => write!(f, "'return' outside of a procedure"),
+            CompileError::BadStep => write!(f, "the step of a for-loop must be a non-zero integer literal or constant"),
+            CompileError::BreakOutsideLoop => write!(f, "'break' outside of a loop"),
+            CompileError::ContinueOutsideLoop => write!(f, "'continue' outside of a loop"),
+            CompileError::UndeclaredRecord(r) => write!(f, "record '{}' is not declared", r),
+            CompileError::DuplicateRecord(r) => write!(f, "record '{}' is declared more than once", r),
+            CompileError::DuplicateField(r, x) => write!(f, "field '{}' of record '{}' appears more than once", x, r),
+            CompileError::MissingField(r, x) => write!(f, "field '{}' of record '{}' is not given a value", x, r),
+            CompileError::NoSuchField(r, x) => write!(f, "record '{}' has no field '{}'", r, x),
+            CompileError::UnknownField(x) => write!(f, "no record has a field '{}'", x),
+            CompileError::DuplicateCase(n) => write!(f, "the match has more than one case for {}", n),
+            CompileError::DuplicateDefault => write!(f, "the match has more than one default case"),
+            CompileError::MissingDefault => write!(f, "the match has no default case '_'"),
+        }
+    }
+}
+
+// The name and fields of a record type, as indices into the table of string constants. A field is
+// found by looking up its name, so that the same instruction works on any record that has it.
+#[derive(Debug)]
+pub struct Layout {
+    pub name: usize,
+    pub fields: Vec<usize>,
+}
+
+// The targets of a 'Switch' instruction, as offsets from it. A value v branches to
+// 'targets[v - low]', or to 'default' if that is out of range.
+#[derive(Debug)]
+pub struct JumpTable {
+    pub low: i64,
+    pub targets: Vec<isize>,
+    pub default: isize,
+}
+
+// The output of the compiler: bytecode, plus the tables of string constants, record layouts and
+// jump tables that it refers to.
+pub struct Code {
+    pub insns: Vec<Insn>,
+    pub strings: Vec<String>,
+    pub layouts: Vec<Layout>,
+    pub tables: Vec<JumpTable>,
+    // The statement that each instruction was compiled from, for reporting runtime errors.
+    pub spans: Vec<Span>,
+}
+
+// The branches out of a loop body that need to be patched once the loop has been compiled.
+struct LoopContext {
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+// What a name refers to.
+#[derive(Clone)]
+enum Binding {
+    Local(usize),
+    Const(Value),
+}
+
+// The variables declared in a block. Slots are handed out in stack order, so a scope owns every
+// slot from 'base' up, and they can all be reused once the block ends.
+struct Scope {
+    vars: HashMap<Var, usize>,
+    base: usize,
 }
 
 pub struct Compiler {
     code: Vec<Insn>,
-    slots: HashMap<Var, usize>,
-    num_slots: usize,
+    strings: Vec<String>,
+    layouts: Vec<Layout>,
+    tables: Vec<JumpTable>,
+    spans: Vec<Span>,
+    // The statement being compiled.
+    span: Span,
+    // The scopes of the frame currently being compiled, innermost last.
+    scopes: Vec<Scope>,
+    next_slot: usize,
+    // The most slots that the current frame has needed at once.
+    frame_size: usize,
+    // 'Enter' and 'Exit' instructions of the current frame. The size of the frame is only known
+    // once all of it has been compiled, so these are patched afterwards.
+    frame_insns: Vec<usize>,
+    // The values of the program's named constants. These are visible everywhere, unless shadowed
+    // by a local variable.
+    consts: HashMap<Var, Value>,
+    // The index of each record type's layout, and the names of its fields.
+    records: HashMap<Var, (usize, Vec<Var>)>,
+    // The loops enclosing the statement currently being compiled, innermost last.
+    loops: Vec<LoopContext>,
+    // Number of parameters of each procedure. These are collected before generating any code, so
+    // that a call may refer to a procedure declared after it.
+    arities: HashMap<Var, usize>,
+    // Address of the first instruction of each procedure that has been compiled so far.
+    entries: HashMap<Var, usize>,
+    // 'Call' instructions that need to be patched with the address of their target once every
+    // procedure has been compiled.
+    calls: Vec<(usize, Var)>,
+    in_proc: bool,
+    // The arithmetic that constants are evaluated with, which should match the VM's.
+    mode: IntMode,
 }
 
 impl Compiler {
-    pub fn new() -> Self {
-        Compiler { code: Vec::new(), slots: HashMap::new(), num_slots: 0 }
+    pub fn new(mode: IntMode) -> Self {
+        Compiler {
+            code: Vec::new(),
+            strings: Vec::new(),
+            layouts: Vec::new(),
+            tables: Vec::new(),
+            spans: Vec::new(),
+            span: Span { start: 0, end: 0 },
+            scopes: Vec::new(),
+            next_slot: 0,
+            frame_size: 0,
+            frame_insns: Vec::new(),
+            consts: HashMap::new(),
+            records: HashMap::new(),
+            loops: Vec::new(),
+            arities: HashMap::new(),
+            entries: HashMap::new(),
+            calls: Vec::new(),
+            in_proc: false,
+            mode,
+        }
     }
 
     fn emit(&mut self, i: Insn) {
-        self.code.push(i)
+        self.code.push(i);
+        self.spans.push(self.span);
     }
 
     fn here(&self) -> usize {
@@ -46,57 +252,434 @@ impl Compiler {
         (to as isize) - (from as isize)
     }
 
-    pub fn output(self) -> Vec<Insn> {
-        self.code
+    fn patch_branch(&mut self, at: usize, to: usize) {
+        let offset = self.branch_offset(at, to);
+        self.code[at] = match self.code[at] {
+            Insn::Branch(_) => Insn::Branch(offset),
+            Insn::BranchZero(_) => Insn::BranchZero(offset),
+            insn => unreachable!("{:?} is not a branch", insn),
+        };
     }
 
-    fn compile_exp(&mut self, e: &Expr) {
-        match e {
-            Expr::Var(x) => {
-                let slot = self.slots.get(x).unwrap();
-                self.emit(Insn::GetLocal(*slot));
+    // The statement being compiled, e.g. the one that caused a compile error.
+    pub fn location(&self) -> Span {
+        self.span
+    }
+
+    pub fn output(self) -> Code {
+        Code { insns: self.code, strings: self.strings, layouts: self.layouts, tables: self.tables, spans: self.spans }
+    }
+
+    // Find the index of a string in the constant table, adding it if necessary.
+    fn string_constant(&mut self, s: &str) -> usize {
+        match self.strings.iter().position(|t| t == s) {
+            Some(i) => i,
+            None => {
+                self.strings.push(s.to_string());
+                self.strings.len() - 1
+            },
+        }
+    }
+
+    // The innermost declaration of a name.
+    fn lookup(&self, x: &Var) -> Result<Binding, CompileError> {
+        if let Some(slot) = self.scopes.iter().rev().find_map(|scope| scope.vars.get(x)) {
+            return Ok(Binding::Local(*slot));
+        }
+        match self.consts.get(x) {
+            Some(v) => Ok(Binding::Const(v.clone())),
+            None => Err(CompileError::UndeclaredVariable(x.clone())),
+        }
+    }
+
+    // The slot of a variable that is about to be assigned to.
+    fn slot(&self, x: &Var) -> Result<usize, CompileError> {
+        match self.lookup(x)? {
+            Binding::Local(slot) => Ok(slot),
+            Binding::Const(_) => Err(CompileError::AssignToConstant(x.clone())),
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(Scope { vars: HashMap::new(), base: self.next_slot });
+    }
+
+    fn end_scope(&mut self) {
+        let scope = self.scopes.pop().unwrap();
+        self.next_slot = scope.base;
+    }
+
+    fn alloc_slot(&mut self) -> usize {
+        let slot = self.next_slot;
+        self.next_slot += 1;
+        self.frame_size = self.frame_size.max(self.next_slot);
+        slot
+    }
+
+    // Give a variable a fresh slot in the innermost scope. Any earlier declaration with the same
+    // name is shadowed, but keeps its own slot.
+    fn declare(&mut self, x: &Var) -> usize {
+        let slot = self.alloc_slot();
+        self.scopes.last_mut().unwrap().vars.insert(x.clone(), slot);
+        slot
+    }
+
+    fn begin_frame(&mut self) {
+        self.scopes.clear();
+        self.next_slot = 0;
+        self.frame_size = 0;
+        self.begin_scope();
+    }
+
+    fn emit_frame_insn(&mut self, i: Insn) {
+        self.frame_insns.push(self.here());
+        self.emit(i);
+    }
+
+    // Fill in the size of the frame now that it is known.
+    fn end_frame(&mut self) {
+        let n = self.frame_size;
+        for at in std::mem::take(&mut self.frame_insns) {
+            self.code[at] = match self.code[at] {
+                Insn::Enter(_) => Insn::Enter(n),
+                Insn::Exit(_) => Insn::Exit(n),
+                i => i,
+            };
+        }
+        self.scopes.clear();
+    }
+
+    fn define_record(&mut self, r: &Record) -> Result<(), CompileError> {
+        self.span = r.span;
+        if self.records.contains_key(&r.name) {
+            return Err(CompileError::DuplicateRecord(r.name.clone()));
+        }
+        for (i, x) in r.fields.iter().enumerate() {
+            if r.fields[..i].contains(x) {
+                return Err(CompileError::DuplicateField(r.name.clone(), x.clone()));
             }
+        }
+        let layout = Layout {
+            name: self.string_constant(&r.name.0),
+            fields: r.fields.iter().map(|x| self.string_constant(&x.0)).collect(),
+        };
+        self.layouts.push(layout);
+        self.records.insert(r.name.clone(), (self.layouts.len() - 1, r.fields.clone()));
+        Ok(())
+    }
+
+    // The string constant naming a field, which must belong to some record.
+    fn field(&mut self, x: &Var) -> Result<usize, CompileError> {
+        if !self.records.values().any(|(_, fields)| fields.contains(x)) {
+            return Err(CompileError::UnknownField(x.clone()));
+        }
+        Ok(self.string_constant(&x.0))
+    }
+
+    // Constants are evaluated in order, so an initializer may refer to the constants before it.
+    fn define_const(&mut self, c: &Const) -> Result<(), CompileError> {
+        self.span = c.span;
+        if self.consts.contains_key(&c.name) {
+            return Err(CompileError::DuplicateConstant(c.name.clone()));
+        }
+        let v = self.eval_const(&c.name, &c.value)?;
+        // The value becomes a literal in the bytecode, so it must fit in one.
+        if let Value::Big(n) = v {
+            return Err(CompileError::ConstantFault(c.name.clone(), RuntimeError::IntTooLarge(n)));
+        }
+        self.consts.insert(c.name.clone(), v);
+        Ok(())
+    }
+
+    // Evaluate the initializer of the constant 'name', with the same results as the VM would
+    // give.
+    fn eval_const(&self, name: &Var, e: &Expr) -> Result<Value, CompileError> {
+        let fault = |err| CompileError::ConstantFault(name.clone(), err);
+        let truth = |v: Value| v != Value::Int(0);
+        let v = match e {
+            Expr::Num(n) => Value::Int(*n),
+            Expr::Float(f) => Value::Float(*f),
+            Expr::Bool(b) => Value::Int(*b as i64),
+            Expr::Var(x) => self.consts.get(x).cloned().ok_or_else(|| CompileError::NotConstant(name.clone()))?,
+            Expr::BinOp(op, e1, e2) => {
+                let x = self.eval_const(name, e1)?;
+                let y = self.eval_const(name, e2)?;
+                binary(binop_insn(*op), x, y, self.mode).map_err(fault)?
+            },
+            Expr::Neg(e) => unary(Insn::Neg, self.eval_const(name, e)?, self.mode).map_err(fault)?,
+            Expr::ToFloat(e) => unary(Insn::IntToFloat, self.eval_const(name, e)?, self.mode).map_err(fault)?,
+            Expr::ToInt(e) => unary(Insn::FloatToInt, self.eval_const(name, e)?, self.mode).map_err(fault)?,
+            Expr::And(e1, e2) => {
+                Value::Int((truth(self.eval_const(name, e1)?) && truth(self.eval_const(name, e2)?)) as i64)
+            },
+            Expr::Or(e1, e2) => {
+                Value::Int((truth(self.eval_const(name, e1)?) || truth(self.eval_const(name, e2)?)) as i64)
+            },
+            Expr::Not(e) => Value::Int(!truth(self.eval_const(name, e)?) as i64),
+            Expr::If(c, e1, e2) => {
+                if truth(self.eval_const(name, c)?) {
+                    self.eval_const(name, e1)?
+                } else {
+                    self.eval_const(name, e2)?
+                }
+            },
+            Expr::Builtin(b, args) => {
+                let insn = builtin_insn(*b, args)?;
+                let mut vs = Vec::new();
+                for arg in args {
+                    vs.push(self.eval_const(name, arg)?);
+                }
+                let mut vs = vs.into_iter();
+                match b {
+                    // The arguments and input are only known when the program is run.
+                    Builtin::Argc | Builtin::Eof => return Err(CompileError::NotConstant(name.clone())),
+                    Builtin::Abs => unary(insn, vs.next().unwrap(), self.mode),
+                    Builtin::Min | Builtin::Max | Builtin::Pow => {
+                        binary(insn, vs.next().unwrap(), vs.next().unwrap(), self.mode)
+                    },
+                }.map_err(fault)?
+            },
+            Expr::Input(_) | Expr::Call(_, _) | Expr::NewArray(_, _) | Expr::Index(_, _) | Expr::Len(_) |
+            Expr::Record(_, _) | Expr::Field(_, _) => {
+                return Err(CompileError::NotConstant(name.clone()))
+            },
+        };
+        Ok(v)
+    }
+
+    fn compile_exp(&mut self, e: &Expr) -> Result<(), CompileError> {
+        match e {
+            Expr::Var(x) => match self.lookup(x)? {
+                Binding::Local(slot) => self.emit(Insn::GetLocal(slot)),
+                Binding::Const(Value::Float(f)) => self.emit(Insn::LiteralFloat(f)),
+                Binding::Const(v) => self.emit(Insn::Literal(v.as_int())),
+            },
             Expr::Num(i) => self.emit(Insn::Literal(*i)),
+            Expr::Float(f) => self.emit(Insn::LiteralFloat(*f)),
+            Expr::Bool(b) => self.emit(Insn::Literal(*b as i64)),
             Expr::BinOp(b, e1, e2) => {
-                self.compile_exp(e1);
-                self.compile_exp(e2);
-                match b {
-                    BinOp::Add => self.emit(Insn::Add),
-                    BinOp::Sub => self.emit(Insn::Sub),
-                    BinOp::Mul => self.emit(Insn::Mul),
-                    BinOp::Lt => self.emit(Insn::Lt),
-                    BinOp::Gt => self.emit(Insn::Gt),
-                }
+                self.compile_exp(e1)?;
+                self.compile_exp(e2)?;
+                self.emit(binop_insn(*b));
             },
+            Expr::Neg(e) => {
+                self.compile_exp(e)?;
+                self.emit(Insn::Neg);
+            }
             Expr::Input(e) => {
-                self.compile_exp(e);
+                self.compile_exp(e)?;
                 self.emit(Insn::Input);
             }
+            Expr::Call(f, args) => self.compile_call(f, args)?,
+            Expr::Builtin(b, args) => {
+                let insn = builtin_insn(*b, args)?;
+                for arg in args {
+                    self.compile_exp(arg)?;
+                }
+                self.emit(insn);
+            }
+            Expr::NewArray(n, fill) => {
+                self.compile_exp(n)?;
+                match fill {
+                    Some(v) => self.compile_exp(v)?,
+                    None => self.emit(Insn::Literal(0)),
+                }
+                self.emit(Insn::ArrayNew);
+            }
+            Expr::ToFloat(e) => {
+                self.compile_exp(e)?;
+                self.emit(Insn::IntToFloat);
+            }
+            Expr::ToInt(e) => {
+                self.compile_exp(e)?;
+                self.emit(Insn::FloatToInt);
+            }
+            Expr::Index(a, i) => {
+                self.compile_exp(a)?;
+                self.compile_exp(i)?;
+                self.emit(Insn::ArrayGet);
+            }
+            Expr::Len(a) => {
+                self.compile_exp(a)?;
+                self.emit(Insn::ArrayLen);
+            }
+            Expr::Record(name, inits) => self.compile_record(name, inits)?,
+            Expr::Field(r, x) => {
+                self.compile_exp(r)?;
+                let k = self.field(x)?;
+                self.emit(Insn::GetField(k));
+            }
+            Expr::And(e1, e2) => {
+                // e1 && e2 ==> if e1 then e2 != 0 else 0
+                self.compile_exp(e1)?;
+                let branch = self.here();
+                self.emit(Insn::BranchZero(0));
+                self.compile_exp(e2)?;
+                self.emit(Insn::Literal(0));
+                self.emit(Insn::Ne);
+                let skip = self.here();
+                self.emit(Insn::Branch(0));
+                let short = self.here();
+                self.emit(Insn::Literal(0));
+                let end = self.here();
+
+                self.code[branch] = Insn::BranchZero(self.branch_offset(branch, short));
+                self.code[skip] = Insn::Branch(self.branch_offset(skip, end));
+            }
+            Expr::Or(e1, e2) => {
+                // e1 || e2 ==> if e1 then 1 else e2 != 0
+                self.compile_exp(e1)?;
+                let branch = self.here();
+                self.emit(Insn::BranchZero(0));
+                self.emit(Insn::Literal(1));
+                let skip = self.here();
+                self.emit(Insn::Branch(0));
+                let rhs = self.here();
+                self.compile_exp(e2)?;
+                self.emit(Insn::Literal(0));
+                self.emit(Insn::Ne);
+                let end = self.here();
+
+                self.code[branch] = Insn::BranchZero(self.branch_offset(branch, rhs));
+                self.code[skip] = Insn::Branch(self.branch_offset(skip, end));
+            }
+            Expr::Not(e) => {
+                self.compile_exp(e)?;
+                self.emit(Insn::Literal(0));
+                self.emit(Insn::Eq);
+            }
+            Expr::If(c, e1, e2) => {
+                self.compile_exp(c)?;
+                let branch = self.here();
+                self.emit(Insn::BranchZero(0));
+                self.compile_exp(e1)?;
+                let skip = self.here();
+                self.emit(Insn::Branch(0));
+                let e2_start = self.here();
+                self.compile_exp(e2)?;
+                let end = self.here();
+
+                self.code[branch] = Insn::BranchZero(self.branch_offset(branch, e2_start));
+                self.code[skip] = Insn::Branch(self.branch_offset(skip, end));
+            }
+        }
+        Ok(())
+    }
+
+    // Push the arguments in order, then call. The callee leaves its return value on the stack.
+    fn compile_call(&mut self, f: &Var, args: &[Expr]) -> Result<(), CompileError> {
+        let arity = *self.arities.get(f).ok_or_else(|| CompileError::UndefinedProcedure(f.clone()))?;
+        if arity != args.len() {
+            return Err(CompileError::ArityMismatch { name: f.clone(), expected: arity, found: args.len() });
+        }
+        for arg in args {
+            self.compile_exp(arg)?;
+        }
+        // The target is patched in by compile_program.
+        self.calls.push((self.here(), f.clone()));
+        self.emit(Insn::Call(0));
+        Ok(())
+    }
+
+    // Every field of the record must be initialized exactly once. The fields are evaluated in the
+    // order they are written, not the order they are declared in, so the record is allocated
+    // first and kept in a temporary while they are stored.
+    fn compile_record(&mut self, name: &Var, inits: &[(Var, Expr)]) -> Result<(), CompileError> {
+        let (layout, fields) = match self.records.get(name) {
+            Some(record) => record.clone(),
+            None => return Err(CompileError::UndeclaredRecord(name.clone())),
+        };
+        for (i, (x, _)) in inits.iter().enumerate() {
+            if !fields.contains(x) {
+                return Err(CompileError::NoSuchField(name.clone(), x.clone()));
+            }
+            if inits[..i].iter().any(|(y, _)| y == x) {
+                return Err(CompileError::DuplicateField(name.clone(), x.clone()));
+            }
+        }
+        if let Some(x) = fields.iter().find(|x| !inits.iter().any(|(y, _)| y == *x)) {
+            return Err(CompileError::MissingField(name.clone(), x.clone()));
+        }
+
+        self.begin_scope();
+        let tmp = self.alloc_slot();
+        self.emit(Insn::RecordNew(layout));
+        self.emit(Insn::SetLocal(tmp));
+        for (x, e) in inits {
+            self.emit(Insn::GetLocal(tmp));
+            self.compile_exp(e)?;
+            let k = self.string_constant(&x.0);
+            self.emit(Insn::SetField(k));
         }
+        self.emit(Insn::GetLocal(tmp));
+        self.end_scope();
+        Ok(())
     }
 
-    fn compile_stmt(&mut self, s: &Stmt) {
+    fn compile_stmt(&mut self, s: &Stmt) -> Result<(), CompileError> {
         match s {
+            Stmt::Let(x, e) => {
+                // The initializer is compiled first, so that it still sees any outer 'x'.
+                self.compile_exp(e)?;
+                let slot = self.declare(x);
+                self.emit(Insn::SetLocal(slot));
+            },
             Stmt::Assign(x, e) => {
-                self.compile_exp(e);
-                let slot = self.slots.get(x).unwrap();
-                self.emit(Insn::SetLocal(*slot));
+                self.compile_exp(e)?;
+                let slot = self.slot(x)?;
+                self.emit(Insn::SetLocal(slot));
             },
-            Stmt::Print(e) => {
-                self.compile_exp(e);
-                self.emit(Insn::Print)
+            Stmt::Read(x) => {
+                self.emit(Insn::Read);
+                let slot = self.slot(x)?;
+                self.emit(Insn::SetLocal(slot));
             },
+            Stmt::Print(items, newline) => {
+                let mut newline = *newline;
+                for (i, item) in items.iter().enumerate() {
+                    match item {
+                        PrintItem::Str(s) => {
+                            let k = self.string_constant(s);
+                            self.emit(Insn::WriteStr(k));
+                        },
+                        PrintItem::Expr(e) => {
+                            self.compile_exp(e)?;
+                            // A trailing value and the newline can be printed in one go.
+                            if newline && i == items.len() - 1 {
+                                self.emit(Insn::Print);
+                                newline = false;
+                            } else {
+                                self.emit(Insn::Write);
+                            }
+                        },
+                    }
+                }
+                if newline {
+                    self.emit(Insn::Newline);
+                }
+            },
+            Stmt::If(e, bt, bf) if bf.0.is_empty() => {
+                // With no else-branch, there is nothing for the true branch to jump over.
+                self.compile_exp(e)?;
+                let branch = self.here();
+                self.emit(Insn::BranchZero(0));
+
+                self.compile_block(bt)?;
+                let bt_end = self.here();
+
+                self.code[branch] = Insn::BranchZero(self.branch_offset(branch, bt_end));
+            }
             Stmt::If(e, bt, bf) => {
-                self.compile_exp(e);
+                self.compile_exp(e)?;
                 let branch = self.here();
                 self.emit(Insn::BranchZero(0));
 
-                self.compile_block(bt);
+                self.compile_block(bt)?;
                 let bt_end = self.here();
                 self.emit(Insn::Branch(0));
 
                 let bf_start = self.here();
-                self.compile_block(bf);
+                self.compile_block(bf)?;
                 let bf_end = self.here();
 
                 // Patch branches now that we know distance between the labels.
@@ -105,172 +688,1036 @@ impl Compiler {
             }
             Stmt::While(e, b) => {
                 let loop_start = self.here();
-                self.compile_exp(e);
+                self.compile_exp(e)?;
                 let branch = self.here();
                 self.emit(Insn::BranchZero(0));
 
-                self.compile_block(b);
+                let ctx = self.compile_loop_body(b)?;
                 let repeat = self.here();
                 self.emit(Insn::Branch(0));
                 let loop_end = self.here();
 
                 self.code[branch] = Insn::BranchZero(self.branch_offset(branch, loop_end));
                 self.code[repeat] = Insn::Branch(self.branch_offset(repeat, loop_start));
+                self.patch_loop(ctx, loop_start, loop_end);
+            }
+            Stmt::For(x, lo, hi, step, b) => {
+                // The loop counts up to the bound with a positive step, or down to it with a
+                // negative step. Either way the bound itself is excluded. The step may also name a
+                // constant, unless a local variable shadows it.
+                let step = match step.as_deref() {
+                    None => 1,
+                    Some(Expr::Num(n)) if *n != 0 => *n,
+                    Some(Expr::Var(x)) => match self.lookup(x)? {
+                        Binding::Const(Value::Int(n)) if n != 0 => n,
+                        _ => return Err(CompileError::BadStep),
+                    },
+                    Some(_) => return Err(CompileError::BadStep),
+                };
+
+                // The bound is evaluated once, before the first iteration. Neither it nor the
+                // loop variable is in scope for 'lo' and 'hi'.
+                self.compile_exp(lo)?;
+                self.compile_exp(hi)?;
+                self.begin_scope();
+                let bound = self.alloc_slot();
+                let var = self.declare(x);
+                self.emit(Insn::SetLocal(bound));
+                self.emit(Insn::SetLocal(var));
+
+                let loop_start = self.here();
+                self.emit(Insn::GetLocal(var));
+                self.emit(Insn::GetLocal(bound));
+                self.emit(if step > 0 { Insn::Lt } else { Insn::Gt });
+                let branch = self.here();
+                self.emit(Insn::BranchZero(0));
+
+                let ctx = self.compile_loop_body(b)?;
+                let next = self.here();
+                // If the step would take the loop variable past the end of the int range, it would
+                // also take it past the bound, so the loop ends without overflowing. Big ints
+                // cannot overflow.
+                let last = if self.mode == IntMode::Big {
+                    None
+                } else {
+                    self.emit(Insn::GetLocal(var));
+                    if step > 0 {
+                        self.emit(Insn::Literal(i64::MAX - step));
+                        self.emit(Insn::Le);
+                    } else {
+                        self.emit(Insn::Literal(i64::MIN - step));
+                        self.emit(Insn::Ge);
+                    }
+                    let last = self.here();
+                    self.emit(Insn::BranchZero(0));
+                    Some(last)
+                };
+                self.emit(Insn::GetLocal(var));
+                self.emit(Insn::Literal(step));
+                self.emit(Insn::Add);
+                self.emit(Insn::SetLocal(var));
+                let repeat = self.here();
+                self.emit(Insn::Branch(0));
+                let loop_end = self.here();
+
+                self.code[branch] = Insn::BranchZero(self.branch_offset(branch, loop_end));
+                if let Some(last) = last {
+                    self.code[last] = Insn::BranchZero(self.branch_offset(last, loop_end));
+                }
+                self.code[repeat] = Insn::Branch(self.branch_offset(repeat, loop_start));
+                self.patch_loop(ctx, next, loop_end);
+                self.end_scope();
+            }
+            Stmt::Store(a, i, e) => {
+                let slot = self.slot(a)?;
+                self.emit(Insn::GetLocal(slot));
+                self.compile_exp(i)?;
+                self.compile_exp(e)?;
+                self.emit(Insn::ArraySet);
+            },
+            Stmt::SetField(r, x, e) => {
+                let slot = self.slot(r)?;
+                self.emit(Insn::GetLocal(slot));
+                self.compile_exp(e)?;
+                let k = self.field(x)?;
+                self.emit(Insn::SetField(k));
+            },
+            Stmt::Call(f, args) => {
+                self.compile_call(f, args)?;
+                // Discard the return value.
+                self.emit(Insn::Pop);
+            }
+            Stmt::Return(e) => {
+                if !self.in_proc {
+                    return Err(CompileError::ReturnOutsideProcedure);
+                }
+                self.compile_exp(e)?;
+                self.emit_frame_insn(Insn::Exit(0));
+                self.emit(Insn::Ret);
+            }
+            Stmt::Match(e, cases) => self.compile_match(e, cases)?,
+            Stmt::Exit(e) => {
+                self.compile_exp(e)?;
+                self.emit(Insn::HaltWith);
+            }
+            Stmt::Assert(e, span) => {
+                self.compile_exp(e)?;
+                self.emit(Insn::Assert(*span));
+            }
+            Stmt::Break => {
+                let here = self.here();
+                let ctx = self.loops.last_mut().ok_or(CompileError::BreakOutsideLoop)?;
+                ctx.breaks.push(here);
+                self.emit(Insn::Branch(0));
+            }
+            Stmt::Continue => {
+                let here = self.here();
+                let ctx = self.loops.last_mut().ok_or(CompileError::ContinueOutsideLoop)?;
+                ctx.continues.push(here);
+                self.emit(Insn::Branch(0));
             }
         }
+        Ok(())
     }
 
-    fn compile_block(&mut self, b : &Block) {
-        for s in &b.0 {
-            self.compile_stmt(s);
+    // The cases are dispatched on first, and their bodies follow in order, each branching to the
+    // end of the match.
+    fn compile_match(&mut self, e: &Expr, cases: &[(Option<i64>, Block)]) -> Result<(), CompileError> {
+        // The values of the cases, sorted, with the index of their case.
+        let mut values = Vec::new();
+        let mut default = None;
+        for (i, (value, _)) in cases.iter().enumerate() {
+            match value {
+                Some(n) if values.iter().any(|(m, _)| m == n) => return Err(CompileError::DuplicateCase(*n)),
+                Some(n) => values.push((*n, i)),
+                None if default.is_some() => return Err(CompileError::DuplicateDefault),
+                None => default = Some(i),
+            }
         }
+        let default = default.ok_or(CompileError::MissingDefault)?;
+        values.sort();
+
+        self.compile_exp(e)?;
+        // Branches to the start of each case's body, which are patched once it is compiled.
+        let mut branches = Vec::new();
+        let table = if Self::is_dense(&values) {
+            let low = values[0].0;
+            let high = values[values.len() - 1].0;
+            let mut targets = vec![default; (high - low + 1) as usize];
+            for (n, i) in &values {
+                targets[(n - low) as usize] = *i;
+            }
+            self.tables.push(JumpTable { low, targets: Vec::new(), default: 0 });
+            let t = self.tables.len() - 1;
+            let switch = self.here();
+            self.emit(Insn::Switch(t));
+            Some((t, switch, targets))
+        } else {
+            // The value is compared more than once, so it is kept in a temporary.
+            self.begin_scope();
+            let tmp = self.alloc_slot();
+            self.emit(Insn::SetLocal(tmp));
+            self.compile_comparisons(tmp, &values, default, &mut branches);
+            self.end_scope();
+            None
+        };
+
+        let mut starts = Vec::new();
+        let mut ends = Vec::new();
+        for (_, body) in cases {
+            starts.push(self.here());
+            self.compile_block(body)?;
+            ends.push(self.here());
+            self.emit(Insn::Branch(0));
+        }
+        // The last case can fall through to the end instead.
+        self.code.pop();
+        self.spans.pop();
+        ends.pop();
+
+        let end = self.here();
+        for at in ends {
+            self.patch_branch(at, end);
+        }
+        for (at, i) in branches {
+            self.patch_branch(at, starts[i]);
+        }
+        if let Some((t, switch, targets)) = table {
+            self.tables[t].targets = targets.iter().map(|&i| self.branch_offset(switch, starts[i])).collect();
+            self.tables[t].default = self.branch_offset(switch, starts[default]);
+        }
+        Ok(())
     }
 
-    pub fn compile_program(&mut self, p: &Program) {
-        self.assign_slots(p);
+    // A jump table is worth it for a few cases, if at least half of its entries are cases.
+    fn is_dense(values: &[(i64, usize)]) -> bool {
+        match (values.first(), values.last()) {
+            (Some((low, _)), Some((high, _))) => {
+                values.len() >= 3 && (*high as i128 - *low as i128 + 1) <= 2 * values.len() as i128
+            },
+            _ => false,
+        }
+    }
 
-        self.emit(Insn::Enter(self.num_slots));
-        self.compile_block(&p.0);
-        self.emit(Insn::Exit(self.num_slots));
-        self.emit(Insn::Halt);
+    // Binary search for the case of the value in slot 'tmp', among the sorted 'values'. The
+    // branches to each case, or to 'default', are added to 'branches'.
+    fn compile_comparisons(&mut self, tmp: usize, values: &[(i64, usize)], default: usize,
+                           branches: &mut Vec<(usize, usize)>) {
+        if values.len() <= 3 {
+            for (n, i) in values {
+                self.emit(Insn::GetLocal(tmp));
+                self.emit(Insn::Literal(*n));
+                self.emit(Insn::Ne);
+                branches.push((self.here(), *i));
+                self.emit(Insn::BranchZero(0));
+            }
+            branches.push((self.here(), default));
+            self.emit(Insn::Branch(0));
+            return;
+        }
+        let (lower, upper) = values.split_at(values.len() / 2);
+        self.emit(Insn::GetLocal(tmp));
+        self.emit(Insn::Literal(upper[0].0));
+        self.emit(Insn::Lt);
+        let branch = self.here();
+        self.emit(Insn::BranchZero(0));
+        self.compile_comparisons(tmp, lower, default, branches);
+        let upper_start = self.here();
+        self.patch_branch(branch, upper_start);
+        self.compile_comparisons(tmp, upper, default, branches);
     }
 
-    // Traverse the program to assign local slots to each distinct variable.
-    // The implementation currently assigns each distinct variable its own slot, which is correct,
-    // but not optimal. A smarter implementation would use live variable ranges to re-use slots
-    // once their contents are no longer live, similar to register allocation (but without the need
-    // to worry about register spilling.)
-    fn assign_slots(&mut self, p: &Program) {
-        self.assign_slots_block(&p.0);
+    fn compile_loop_body(&mut self, b: &Block) -> Result<LoopContext, CompileError> {
+        self.loops.push(LoopContext { breaks: Vec::new(), continues: Vec::new() });
+        let result = self.compile_block(b);
+        let ctx = self.loops.pop().unwrap();
+        result.map(|()| ctx)
     }
 
-    fn assign_slots_block(&mut self, b: &Block) {
-        for s in &b.0 {
-            self.assign_slots_stmt(s);
+    // Point the 'break' and 'continue' statements of a loop at their targets.
+    fn patch_loop(&mut self, ctx: LoopContext, continue_target: usize, loop_end: usize) {
+        for b in ctx.breaks {
+            self.code[b] = Insn::Branch(self.branch_offset(b, loop_end));
+        }
+        for c in ctx.continues {
+            self.code[c] = Insn::Branch(self.branch_offset(c, continue_target));
         }
     }
 
-    fn assign_slots_stmt(&mut self, s: &Stmt) {
-        match s {
-            Stmt::Assign(x, _e) => {
-                let entry = self.slots.entry(x.clone());
-                // If this variable already has a slot, nothing needs to be done.
-                // Otherwise, we need to assign a new slot.
-                entry.or_insert_with(|| {
-                    let i = self.num_slots;
-                    self.num_slots += 1;
-                    i
-                });
-            },
-            Stmt::Print(_e) => {},
-            Stmt::If(_e, bt, bf) => {
-                self.assign_slots_block(bt);
-                self.assign_slots_block(bf);
+    // Variables declared in a block go out of scope at its end, and their slots are reclaimed.
+    fn compile_block(&mut self, b : &Block) -> Result<(), CompileError> {
+        self.begin_scope();
+        let outer = self.span;
+        for (span, s) in &b.0 {
+            self.span = *span;
+            self.compile_stmt(s)?;
+        }
+        self.span = outer;
+        self.end_scope();
+        Ok(())
+    }
+
+    // Each procedure gets its own frame of locals. The caller has pushed the arguments onto the
+    // stack and the 'Call' instruction has saved the return address, so the prologue allocates
+    // the frame and pops the arguments into their slots.
+    fn compile_proc(&mut self, proc: &Proc) -> Result<(), CompileError> {
+        self.span = proc.span;
+        self.entries.insert(proc.name.clone(), self.here());
+        self.begin_frame();
+
+        // Parameters take the first slots of the frame, in order.
+        for param in &proc.params {
+            if self.scopes[0].vars.contains_key(param) {
+                return Err(CompileError::DuplicateParameter(proc.name.clone(), param.clone()));
             }
-            Stmt::While(_e, b) => {
-                self.assign_slots_block(b);
+            self.declare(param);
+        }
+
+        self.emit_frame_insn(Insn::Enter(0));
+        for param in proc.params.iter().rev() {
+            let slot = self.slot(param)?;
+            self.emit(Insn::SetLocal(slot));
+        }
+        self.in_proc = true;
+        self.compile_block(&proc.body)?;
+        self.in_proc = false;
+
+        // Falling off the end of a procedure returns 0.
+        self.emit(Insn::Literal(0));
+        self.emit_frame_insn(Insn::Exit(0));
+        self.emit(Insn::Ret);
+        self.end_frame();
+        Ok(())
+    }
+
+    pub fn compile_program(&mut self, p: &Program) -> Result<(), CompileError> {
+        for r in &p.records {
+            self.define_record(r)?;
+        }
+        for c in &p.consts {
+            self.define_const(c)?;
+        }
+        for proc in &p.procs {
+            self.span = proc.span;
+            if Builtin::from_name(&proc.name.0).is_some() {
+                return Err(CompileError::BuiltinProcedure(proc.name.clone()));
+            }
+            if self.arities.insert(proc.name.clone(), proc.params.len()).is_some() {
+                return Err(CompileError::DuplicateProcedure(proc.name.clone()));
             }
         }
+
+        self.begin_frame();
+        self.emit_frame_insn(Insn::Enter(0));
+        self.compile_block(&p.body)?;
+        self.emit_frame_insn(Insn::Exit(0));
+        self.emit(Insn::Halt);
+        self.end_frame();
+
+        for proc in &p.procs {
+            self.compile_proc(proc)?;
+        }
+
+        // Now that every procedure has an address, fill in the call targets.
+        for (call, f) in std::mem::take(&mut self.calls) {
+            self.code[call] = Insn::Call(self.entries[&f]);
+        }
+        Ok(())
+    }
+}
+
+
+
+fn binop_insn(op: BinOp) -> Insn {
+    match op {
+        BinOp::Add => Insn::Add,
+        BinOp::Sub => Insn::Sub,
+        BinOp::Mul => Insn::Mul,
+        BinOp::Div => Insn::Div,
+        BinOp::Mod => Insn::Mod,
+        BinOp::BitAnd => Insn::BitAnd,
+        BinOp::BitOr => Insn::BitOr,
+        BinOp::BitXor => Insn::BitXor,
+        BinOp::Shl => Insn::Shl,
+        BinOp::Shr => Insn::Shr,
+        BinOp::Lt => Insn::Lt,
+        BinOp::Gt => Insn::Gt,
+        BinOp::Eq => Insn::Eq,
+        BinOp::Ne => Insn::Ne,
+        BinOp::Le => Insn::Le,
+        BinOp::Ge => Insn::Ge,
+    }
+}
+
+// The instruction for a builtin function, which takes its arguments from the stack.
+fn builtin_insn(b: Builtin, args: &[Expr]) -> Result<Insn, CompileError> {
+    if args.len() != b.arity() {
+        let name = Var(b.name().to_string());
+        return Err(CompileError::ArityMismatch { name, expected: b.arity(), found: args.len() });
+    }
+    let insn = match b {
+        Builtin::Argc => Insn::Argc,
+        Builtin::Eof => Insn::Eof,
+        Builtin::Abs => Insn::Abs,
+        Builtin::Min => Insn::Min,
+        Builtin::Max => Insn::Max,
+        Builtin::Pow => Insn::Pow,
+    };
+    Ok(insn)
+}
+
+// How integer arithmetic behaves when a result does not fit in 64 bits. The bytecode is the same
+// either way.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IntMode {
+    // Wrap around, as two's complement.
+    Wrap,
+    // Stop with a runtime error.
+    Trap,
+    // Clamp to the smallest or largest int.
+    Saturate,
+    // Continue with arbitrary-precision integers, so that every result is exact.
+    Big,
+}
+
+// A runtime value. Arrays and records live in the VM's heap and are referred to by their index
+// there, so copying one aliases it rather than copying its contents.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    // An int that does not fit in 64 bits. These only arise in 'IntMode::Big', and an int that
+    // does fit is always represented as 'Int', so that equal ints compare equal.
+    Big(BigInt),
+    Float(f64),
+    Array(usize),
+    // A record also refers to the layout of its type.
+    Record { layout: usize, r: usize },
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) | Value::Big(_) => "int",
+            Value::Float(_) => "float",
+            Value::Array(_) => "array",
+            Value::Record { .. } => "record",
+        }
+    }
+
+    pub fn from_big(n: BigInt) -> Value {
+        match n.to_i64() {
+            Some(i) => Value::Int(i),
+            None => Value::Big(n),
+        }
+    }
+
+    fn to_big(&self) -> BigInt {
+        match self {
+            Value::Int(i) => BigInt::from(*i),
+            Value::Big(n) => n.clone(),
+            v => panic!("expected an int, but found {}", v.type_name()),
+        }
+    }
+
+    fn as_int(&self) -> i64 {
+        match self {
+            Value::Int(i) => *i,
+            v => panic!("expected an int, but found {}", v.type_name()),
+        }
     }
 }
 
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Big(n) => write!(f, "{}", n),
+            // Floats always have a decimal point, to tell them apart from ints.
+            Value::Float(x) => write!(f, "{:?}", x),
+            Value::Array(r) => write!(f, "<array {}>", r),
+            Value::Record { r, .. } => write!(f, "<record {}>", r),
+        }
+    }
+}
 
+#[derive(Debug)]
+pub enum RuntimeError {
+    TypeMismatch { expected: &'static str, found: &'static str },
+    IndexOutOfBounds { index: i64, len: usize },
+    NegativeLength(i64),
+    // There is not enough memory for an array of this length.
+    ArrayTooLarge(i64),
+    DivisionByZero,
+    BadShift(i64),
+    NegativeExponent(i64),
+    // 'input(i)' past the end of the arguments, or before the start.
+    NoSuchArgument { index: i64, count: usize },
+    // The input stream held something other than an int.
+    BadInput(String),
+    ReadFailed(std::io::Error),
+    // An arbitrary-precision int was used where a 64-bit one is needed, such as an array index.
+    IntTooLarge(BigInt),
+    NoSuchField { record: String, field: String },
+    // A result did not fit in 64 bits, under 'IntMode::Trap'.
+    Overflow,
+    // The span locates the asserted expression in the source.
+    AssertionFailed(Span),
+    // The OS only keeps the low 8 bits of the status, so any other would be misreported.
+    BadExitStatus(i64),
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::TypeMismatch { expected, found } => {
+                write!(f, "expected a value of type {}, but found {}", expected, found)
+            },
+            RuntimeError::IndexOutOfBounds { index, len } => {
+                write!(f, "index {} is out of bounds for an array of length {}", index, len)
+            },
+            RuntimeError::NegativeLength(n) => write!(f, "cannot create an array of length {}", n),
+            RuntimeError::ArrayTooLarge(n) => write!(f, "cannot allocate an array of length {}", n),
+            RuntimeError::DivisionByZero => write!(f, "division by zero"),
+            RuntimeError::BadShift(n) => write!(f, "cannot shift by {} bits", n),
+            RuntimeError::NegativeExponent(n) => write!(f, "cannot raise an int to the power {}", n),
+            RuntimeError::NoSuchArgument { index, count } => {
+                write!(f, "there is no input {}, as only {} were given", index, count)
+            },
+            RuntimeError::BadInput(s) => write!(f, "cannot read '{}' as an int", s),
+            RuntimeError::ReadFailed(err) => write!(f, "cannot read the input: {}", err),
+            RuntimeError::IntTooLarge(n) => write!(f, "{} does not fit in 64 bits", n),
+            RuntimeError::NoSuchField { record, field } => write!(f, "record '{}' has no field '{}'", record, field),
+            RuntimeError::Overflow => write!(f, "integer overflow"),
+            RuntimeError::AssertionFailed(_span) => write!(f, "assertion failed"),
+            RuntimeError::BadExitStatus(n) => write!(f, "exit status {} is not between 0 and 255", n),
+        }
+    }
+}
+
+// The result of a binary instruction. The evaluation of constants shares this with the VM, so that
+// it gets the same results. Both operands must have the same type, except for equality, which
+// is defined on any pair of values: arrays are compared by identity, and a value is never equal
+// to a value of a different type.
+fn binary(insn: Insn, x: Value, y: Value, mode: IntMode) -> Result<Value, RuntimeError> {
+    match (insn, x, y) {
+        (Insn::Eq, x, y) => Ok(Value::Int((x == y) as i64)),
+        (Insn::Ne, x, y) => Ok(Value::Int((x != y) as i64)),
+        (_, Value::Int(x), Value::Int(y)) => int_binary(insn, x, y, mode),
+        (_, x @ (Value::Int(_) | Value::Big(_)), y @ (Value::Int(_) | Value::Big(_))) => {
+            big_binary(insn, x.to_big(), y.to_big()).map(Value::from_big)
+        },
+        (_, Value::Float(x), Value::Float(y)) => float_binary(insn, x, y),
+        (_, Value::Float(_), y) => Err(RuntimeError::TypeMismatch { expected: "float", found: y.type_name() }),
+        (_, Value::Int(_) | Value::Big(_), y) => Err(RuntimeError::TypeMismatch { expected: "int", found: y.type_name() }),
+        (_, x, _) => Err(RuntimeError::TypeMismatch { expected: "int", found: x.type_name() }),
+    }
+}
+
+fn int_binary(insn: Insn, x: i64, y: i64, mode: IntMode) -> Result<Value, RuntimeError> {
+    let (v, overflowed) = match insn {
+        Insn::Add => x.overflowing_add(y),
+        Insn::Sub => x.overflowing_sub(y),
+        Insn::Mul => x.overflowing_mul(y),
+        // Division truncates towards zero, and the remainder has the sign of the dividend.
+        Insn::Div | Insn::Mod if y == 0 => return Err(RuntimeError::DivisionByZero),
+        Insn::Div => x.overflowing_div(y),
+        // The remainder always fits, even that of 'i64::MIN % -1'.
+        Insn::Mod => (x.wrapping_rem(y), false),
+        Insn::BitAnd => (x & y, false),
+        Insn::BitOr => (x | y, false),
+        Insn::BitXor => (x ^ y, false),
+        Insn::Min => (x.min(y), false),
+        Insn::Max => (x.max(y), false),
+        Insn::Pow if y < 0 => return Err(RuntimeError::NegativeExponent(y)),
+        Insn::Pow => int_pow(x, y as u64),
+        // A shift amount must be less than the width of an integer, unless integers have no
+        // width.
+        Insn::Shl | Insn::Shr if !(0..64).contains(&y) => match mode {
+            IntMode::Big => return big_binary(insn, BigInt::from(x), BigInt::from(y)).map(Value::from_big),
+            _ => return Err(RuntimeError::BadShift(y)),
+        },
+        Insn::Shl => (x << y, (x << y) >> y != x),
+        Insn::Shr => (x >> y, false),
+        Insn::Lt => ((x < y) as i64, false),
+        Insn::Gt => ((x > y) as i64, false),
+        Insn::Le => ((x <= y) as i64, false),
+        Insn::Ge => ((x >= y) as i64, false),
+        _ => unreachable!("{:?} is not a binary instruction", insn),
+    };
+    if !overflowed {
+        return Ok(Value::Int(v));
+    }
+    let exact = match (insn, mode) {
+        // An exact power could be far too large to compute, and only its sign is needed.
+        (Insn::Pow, IntMode::Wrap | IntMode::Trap | IntMode::Saturate) => {
+            BigInt::from(if x < 0 && y % 2 == 1 { -1 } else { 1 })
+        },
+        _ => big_binary(insn, BigInt::from(x), BigInt::from(y))?,
+    };
+    overflow(mode, v, exact)
+}
+
+// Exponentiation by squaring, modulo 2^64, and whether the exact result overflowed.
+fn int_pow(x: i64, mut n: u64) -> (i64, bool) {
+    let (mut v, mut base, mut overflowed) = (1i64, x, false);
+    while n > 0 {
+        if n & 1 == 1 {
+            let (w, o) = v.overflowing_mul(base);
+            v = w;
+            overflowed |= o;
+        }
+        n >>= 1;
+        // The base is only squared if it will be multiplied in again, and the result is then
+        // at least as large as it is.
+        if n > 0 {
+            let (w, o) = base.overflowing_mul(base);
+            base = w;
+            overflowed |= o;
+        }
+    }
+    (v, overflowed)
+}
+
+// The result of an int operation whose exact result does not fit in 64 bits, given what that
+// result is modulo 2^64.
+fn overflow(mode: IntMode, wrapped: i64, exact: BigInt) -> Result<Value, RuntimeError> {
+    match mode {
+        IntMode::Wrap => Ok(Value::Int(wrapped)),
+        IntMode::Trap => Err(RuntimeError::Overflow),
+        IntMode::Saturate if exact.is_negative() => Ok(Value::Int(i64::MIN)),
+        IntMode::Saturate => Ok(Value::Int(i64::MAX)),
+        IntMode::Big => Ok(Value::from_big(exact)),
+    }
+}
+
+// Arithmetic on arbitrary-precision ints. This has the same results as 'int_binary', except that
+// nothing overflows.
+fn big_binary(insn: Insn, x: BigInt, y: BigInt) -> Result<BigInt, RuntimeError> {
+    let v = match insn {
+        Insn::Add => x + y,
+        Insn::Sub => x - y,
+        Insn::Mul => x * y,
+        Insn::Div | Insn::Mod if y.is_zero() => return Err(RuntimeError::DivisionByZero),
+        Insn::Div => x / y,
+        Insn::Mod => x % y,
+        Insn::BitAnd => x & y,
+        Insn::BitOr => x | y,
+        Insn::BitXor => x ^ y,
+        Insn::Min => x.min(y),
+        Insn::Max => x.max(y),
+        Insn::Pow => {
+            let n = y.to_i64().ok_or(RuntimeError::IntTooLarge(y))?;
+            if n < 0 {
+                return Err(RuntimeError::NegativeExponent(n));
+            }
+            num_traits::Pow::pow(x, n as u64)
+        },
+        Insn::Shl | Insn::Shr => {
+            let amount = y.to_i64().ok_or(RuntimeError::IntTooLarge(y))?;
+            if amount < 0 {
+                return Err(RuntimeError::BadShift(amount));
+            }
+            // Shifting right rounds towards negative infinity, as it does for 64-bit ints.
+            match insn {
+                Insn::Shl => x << amount,
+                _ => x >> amount,
+            }
+        },
+        Insn::Lt => BigInt::from((x < y) as i64),
+        Insn::Gt => BigInt::from((x > y) as i64),
+        Insn::Le => BigInt::from((x <= y) as i64),
+        Insn::Ge => BigInt::from((x >= y) as i64),
+        _ => unreachable!("{:?} is not a binary instruction", insn),
+    };
+    Ok(v)
+}
+
+// Float arithmetic follows IEEE 754, so division by zero gives an infinity or NaN rather than
+// an error.
+fn float_binary(insn: Insn, x: f64, y: f64) -> Result<Value, RuntimeError> {
+    let v = match insn {
+        Insn::Add => Value::Float(x + y),
+        Insn::Sub => Value::Float(x - y),
+        Insn::Mul => Value::Float(x * y),
+        Insn::Div => Value::Float(x / y),
+        Insn::Mod => Value::Float(x % y),
+        // If one operand is NaN, the result is the other.
+        Insn::Min => Value::Float(x.min(y)),
+        Insn::Max => Value::Float(x.max(y)),
+        Insn::Pow => Value::Float(x.powf(y)),
+        Insn::Lt => Value::Int((x < y) as i64),
+        Insn::Gt => Value::Int((x > y) as i64),
+        Insn::Le => Value::Int((x <= y) as i64),
+        Insn::Ge => Value::Int((x >= y) as i64),
+        Insn::BitAnd | Insn::BitOr | Insn::BitXor | Insn::Shl | Insn::Shr => {
+            return Err(RuntimeError::TypeMismatch { expected: "int", found: "float" })
+        },
+        _ => unreachable!("{:?} is not a binary instruction", insn),
+    };
+    Ok(v)
+}
+
+fn unary(insn: Insn, x: Value, mode: IntMode) -> Result<Value, RuntimeError> {
+    match (insn, x) {
+        (Insn::Neg, Value::Int(x)) => match x.overflowing_neg() {
+            (v, false) => Ok(Value::Int(v)),
+            (v, true) => overflow(mode, v, -BigInt::from(x)),
+        },
+        (Insn::Neg, Value::Big(x)) => Ok(Value::from_big(-x)),
+        (Insn::Neg, Value::Float(x)) => Ok(Value::Float(-x)),
+        (Insn::Abs, Value::Int(x)) => match x.overflowing_abs() {
+            (v, false) => Ok(Value::Int(v)),
+            (v, true) => overflow(mode, v, BigInt::from(x).abs()),
+        },
+        (Insn::Abs, Value::Big(x)) => Ok(Value::from_big(x.abs())),
+        (Insn::Abs, Value::Float(x)) => Ok(Value::Float(x.abs())),
+        (Insn::IntToFloat, Value::Int(x)) => Ok(Value::Float(x as f64)),
+        (Insn::IntToFloat, Value::Big(x)) => Ok(Value::Float(x.to_f64().unwrap_or(f64::NAN))),
+        (Insn::FloatToInt, Value::Float(x)) => match mode {
+            // Finite floats convert exactly when ints are unbounded.
+            IntMode::Big if x.is_finite() => Ok(Value::from_big(BigInt::from_f64(x).unwrap())),
+            IntMode::Trap if !(i64::MIN as f64..-(i64::MIN as f64)).contains(&x) => Err(RuntimeError::Overflow),
+            // Otherwise, out-of-range floats saturate, and NaN becomes 0. There is no sensible
+            // way to wrap them.
+            _ => Ok(Value::Int(x as i64)),
+        },
+        (Insn::FloatToInt, x) => Err(RuntimeError::TypeMismatch { expected: "float", found: x.type_name() }),
+        (_, x) => Err(RuntimeError::TypeMismatch { expected: "int", found: x.type_name() }),
+    }
+}
 
 pub struct VM {
-    stack: Vec<i64>,
-    locals: Vec<i64>,
+    stack: Vec<Value>,
+    strings: Vec<String>, // table of string constants
+    layouts: Vec<Layout>, // the fields of each record type
+    tables: Vec<JumpTable>, // the targets of 'Switch' instructions
+    spans: Vec<Span>, // the statement that each instruction was compiled from
+    locals: Vec<Value>,
+    heap: Vec<Vec<Value>>, // arrays and records allocated by the program. Never freed.
     code: Vec<Insn>,
     pc: usize, // index of current instruction in `code`
     fp: usize, // offset of current frame in `locals`
-    args: Vec<i64>, // command-line arguments provided as inputs to the program
+    args: Vec<Value>, // command-line arguments provided as inputs to the program
+    input: Box<dyn BufRead>, // the stream that 'Read' takes ints from, stdin unless replaced
+    tokens: Vec<String>, // the rest of the current line of input, in reverse
+    eof: bool, // set once a 'Read' finds nothing left
+    status: i64, // exit status of the program, set by 'HaltWith'
+    mode: IntMode,
 }
 
 impl VM {
-    pub fn new(code: Vec<Insn>, args: Vec<i64>) -> Self {
-        VM { stack: Vec::new(), locals: Vec::new(), code: code, pc: 0, fp: 0, args: args }
+    pub fn new(code: Code, args: Vec<Value>, mode: IntMode) -> Self {
+        VM {
+            stack: Vec::new(),
+            strings: code.strings,
+            layouts: code.layouts,
+            tables: code.tables,
+            spans: code.spans,
+            locals: Vec::new(),
+            heap: Vec::new(),
+            code: code.insns,
+            pc: 0,
+            fp: 0,
+            args,
+            input: Box::new(std::io::stdin().lock()),
+            tokens: Vec::new(),
+            eof: false,
+            status: 0,
+            mode,
+        }
     }
 
-    fn step(&mut self) -> Option<usize> {
+    // Take the ints for 'Read' from another stream than stdin.
+    pub fn set_input(&mut self, input: Box<dyn BufRead>) {
+        self.input = input;
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap()
+    }
+
+    // The next int from the input. Ints are separated by whitespace, and a line is only read once
+    // the previous one is used up, so that the program can prompt for it.
+    fn read(&mut self) -> Result<Value, RuntimeError> {
+        while self.tokens.is_empty() {
+            let mut line = String::new();
+            if self.input.read_line(&mut line).map_err(RuntimeError::ReadFailed)? == 0 {
+                self.eof = true;
+                return Ok(Value::Int(0));
+            }
+            self.tokens = line.split_whitespace().rev().map(String::from).collect();
+        }
+        let token = self.tokens.pop().unwrap();
+        match (token.parse::<i64>(), token.parse::<BigInt>()) {
+            (Ok(n), _) => Ok(Value::Int(n)),
+            (Err(_), Ok(n)) if self.mode == IntMode::Big => Ok(Value::from_big(n)),
+            (Err(_), Ok(n)) => Err(RuntimeError::IntTooLarge(n)),
+            (Err(_), Err(_)) => Err(RuntimeError::BadInput(token)),
+        }
+    }
+
+    fn pop_int(&mut self) -> Result<i64, RuntimeError> {
+        match self.pop() {
+            Value::Int(i) => Ok(i),
+            Value::Big(n) => Err(RuntimeError::IntTooLarge(n)),
+            v => Err(RuntimeError::TypeMismatch { expected: "int", found: v.type_name() }),
+        }
+    }
+
+    fn pop_array(&mut self) -> Result<usize, RuntimeError> {
+        match self.pop() {
+            Value::Array(r) => Ok(r),
+            v => Err(RuntimeError::TypeMismatch { expected: "array", found: v.type_name() }),
+        }
+    }
+
+    fn pop_record(&mut self) -> Result<(usize, usize), RuntimeError> {
+        match self.pop() {
+            Value::Record { layout, r } => Ok((layout, r)),
+            v => Err(RuntimeError::TypeMismatch { expected: "record", found: v.type_name() }),
+        }
+    }
+
+    // Saved frame pointers and return addresses are stored in `locals` alongside ordinary values.
+    fn pop_saved(&mut self) -> usize {
+        match self.locals.pop().unwrap() {
+            Value::Int(i) => i as usize, // hmm. Annoying cast.
+            v => panic!("corrupt frame: found {} instead of a saved address", v.type_name()),
+        }
+    }
+
+    fn array_index(&self, r: usize, index: i64) -> Result<usize, RuntimeError> {
+        let len = self.heap[r].len();
+        if index < 0 || index as usize >= len {
+            return Err(RuntimeError::IndexOutOfBounds { index, len });
+        }
+        Ok(index as usize)
+    }
+
+    // The position of the field named by string constant 'k' in a record with the given layout.
+    fn field_index(&self, layout: usize, k: usize) -> Result<usize, RuntimeError> {
+        let layout = &self.layouts[layout];
+        layout.fields.iter().position(|&f| f == k).ok_or_else(|| RuntimeError::NoSuchField {
+            record: self.strings[layout.name].clone(),
+            field: self.strings[k].clone(),
+        })
+    }
+
+    // Records may refer to themselves, so a record that is already being shown further out is
+    // abbreviated.
+    fn show(&self, v: Value, outer: &mut Vec<usize>) -> String {
+        match v {
+            Value::Int(_) | Value::Big(_) | Value::Float(_) => v.to_string(),
+            Value::Array(r) => {
+                let elems: Vec<String> = self.heap[r].iter().map(|e| self.show(e.clone(), outer)).collect();
+                format!("[{}]", elems.join(", "))
+            },
+            Value::Record { layout, r } => {
+                let layout = &self.layouts[layout];
+                let name = &self.strings[layout.name];
+                if outer.contains(&r) {
+                    return format!("{} {{ ... }}", name);
+                }
+                outer.push(r);
+                let fields: Vec<String> = layout.fields.iter().zip(&self.heap[r])
+                    .map(|(&k, e)| format!("{}: {}", self.strings[k], self.show(e.clone(), outer)))
+                    .collect();
+                outer.pop();
+                format!("{} {{ {} }}", name, fields.join(", "))
+            },
+        }
+    }
+
+    fn step(&mut self) -> Result<Option<usize>, RuntimeError> {
         let insn = self.code[self.pc];
         match insn {
-            Insn::Halt => return None,
-            Insn::Literal(i) => {
-                self.stack.push(i);
+            Insn::Halt => return Ok(None),
+            Insn::HaltWith => {
+                let status = self.pop_int()?;
+                if !(0..=255).contains(&status) {
+                    return Err(RuntimeError::BadExitStatus(status));
+                }
+                self.status = status;
+                return Ok(None);
             },
-            Insn::Add => {
-                let y = self.stack.pop().unwrap();
-                let x = self.stack.pop().unwrap();
-                self.stack.push(x + y);
+            Insn::Assert(span) => {
+                if self.pop_int()? == 0 {
+                    return Err(RuntimeError::AssertionFailed(span));
+                }
             },
-            Insn::Sub => {
-                let y = self.stack.pop().unwrap();
-                let x = self.stack.pop().unwrap();
-                self.stack.push(x - y);
+            Insn::Literal(i) => {
+                self.stack.push(Value::Int(i));
             },
-            Insn::Mul => {
-                let y = self.stack.pop().unwrap();
-                let x = self.stack.pop().unwrap();
-                self.stack.push(x * y);
+            Insn::LiteralFloat(x) => {
+                self.stack.push(Value::Float(x));
             },
-            Insn::Lt => {
-                let y = self.stack.pop().unwrap();
-                let x = self.stack.pop().unwrap();
-                self.stack.push(if x < y { 1 } else { 0 });
+            Insn::Add | Insn::Sub | Insn::Mul | Insn::Div | Insn::Mod |
+            Insn::BitAnd | Insn::BitOr | Insn::BitXor | Insn::Shl | Insn::Shr |
+            Insn::Lt | Insn::Gt | Insn::Eq | Insn::Ne | Insn::Le | Insn::Ge |
+            Insn::Min | Insn::Max | Insn::Pow => {
+                let y = self.pop();
+                let x = self.pop();
+                self.stack.push(binary(insn, x, y, self.mode)?);
             },
-            Insn::Gt => {
-                let y = self.stack.pop().unwrap();
-                let x = self.stack.pop().unwrap();
-                self.stack.push(if x > y { 1 } else { 0 });
+            Insn::Neg | Insn::Abs | Insn::IntToFloat | Insn::FloatToInt => {
+                let x = self.pop();
+                self.stack.push(unary(insn, x, self.mode)?);
             },
             Insn::Print => {
-                let x = self.stack.pop().unwrap();
-                println!("{}", x);
+                let x = self.pop();
+                println!("{}", self.show(x, &mut Vec::new()));
+            },
+            Insn::Write => {
+                let x = self.pop();
+                print!("{}", self.show(x, &mut Vec::new()));
+            },
+            Insn::WriteStr(k) => {
+                print!("{}", self.strings[k]);
+            },
+            Insn::Newline => {
+                println!();
             },
             Insn::Enter(n) => {
-                self.locals.push(self.fp as i64); // hmm. Annoying cast.
+                self.locals.push(Value::Int(self.fp as i64)); // hmm. Annoying cast.
                 self.fp = self.locals.len();
                 for _ in 0..n {
-                    self.locals.push(0);
+                    self.locals.push(Value::Int(0));
                 }
             },
             Insn::Exit(n) => {
                 for _ in 0..n {
                     self.locals.pop().unwrap();
                 }
-                self.fp = self.locals.pop().unwrap() as usize; // hmm. Annoying cast.
+                self.fp = self.pop_saved();
             },
             Insn::GetLocal(x) => {
-                self.stack.push(self.locals[self.fp + x]);
+                self.stack.push(self.locals[self.fp + x].clone());
             },
             Insn::SetLocal(x) => {
-                self.locals[self.fp + x] = self.stack.pop().unwrap();
+                self.locals[self.fp + x] = self.pop();
             },
             Insn::Input => {
-                let index = self.stack.pop().unwrap();
-                self.stack.push(self.args[index as usize]);
+                let index = self.pop_int()?;
+                let count = self.args.len();
+                let arg = usize::try_from(index).ok().and_then(|i| self.args.get(i));
+                let arg = arg.ok_or(RuntimeError::NoSuchArgument { index, count })?;
+                self.stack.push(arg.clone());
+            },
+            Insn::Argc => {
+                self.stack.push(Value::Int(self.args.len() as i64));
+            },
+            Insn::Read => {
+                let v = self.read()?;
+                self.stack.push(v);
             },
-            Insn::Branch(n) => return Some(self.pc.wrapping_add_signed(n)),
+            Insn::Eof => {
+                self.stack.push(Value::Int(self.eof as i64));
+            },
+            Insn::Branch(n) => return Ok(Some(self.pc.wrapping_add_signed(n))),
             Insn::BranchZero(n) => {
-                let x = self.stack.pop().unwrap();
+                let x = self.pop_int()?;
                 if x == 0 {
-                    return Some(self.pc.wrapping_add_signed(n));
+                    return Ok(Some(self.pc.wrapping_add_signed(n)));
                 }
             },
-
+            Insn::Switch(t) => {
+                let v = self.pop();
+                let table = &self.tables[t];
+                // A big int is never in the range of the table.
+                let n = match v {
+                    Value::Int(x) => x.checked_sub(table.low)
+                        .and_then(|i| usize::try_from(i).ok())
+                        .and_then(|i| table.targets.get(i))
+                        .map_or(table.default, |n| *n),
+                    Value::Big(_) => table.default,
+                    v => return Err(RuntimeError::TypeMismatch { expected: "int", found: v.type_name() }),
+                };
+                return Ok(Some(self.pc.wrapping_add_signed(n)));
+            },
+            Insn::Call(target) => {
+                // The return address is saved in the locals, below the callee's frame.
+                self.locals.push(Value::Int((self.pc + 1) as i64)); // hmm. Annoying cast.
+                return Ok(Some(target));
+            },
+            Insn::Ret => {
+                return Ok(Some(self.pop_saved()));
+            },
+            Insn::Pop => {
+                self.pop();
+            },
+            Insn::ArrayNew => {
+                let fill = self.pop();
+                let len = self.pop_int()?;
+                if len < 0 {
+                    return Err(RuntimeError::NegativeLength(len));
+                }
+                let mut array = Vec::new();
+                array.try_reserve_exact(len as usize).map_err(|_| RuntimeError::ArrayTooLarge(len))?;
+                array.resize(len as usize, fill);
+                self.heap.push(array);
+                self.stack.push(Value::Array(self.heap.len() - 1));
+            },
+            Insn::ArrayGet => {
+                let index = self.pop_int()?;
+                let r = self.pop_array()?;
+                let i = self.array_index(r, index)?;
+                self.stack.push(self.heap[r][i].clone());
+            },
+            Insn::ArraySet => {
+                let v = self.pop();
+                let index = self.pop_int()?;
+                let r = self.pop_array()?;
+                let i = self.array_index(r, index)?;
+                self.heap[r][i] = v;
+            },
+            Insn::ArrayLen => {
+                let r = self.pop_array()?;
+                self.stack.push(Value::Int(self.heap[r].len() as i64));
+            },
+            Insn::RecordNew(layout) => {
+                self.heap.push(vec![Value::Int(0); self.layouts[layout].fields.len()]);
+                self.stack.push(Value::Record { layout, r: self.heap.len() - 1 });
+            },
+            Insn::GetField(k) => {
+                let (layout, r) = self.pop_record()?;
+                let i = self.field_index(layout, k)?;
+                self.stack.push(self.heap[r][i].clone());
+            },
+            Insn::SetField(k) => {
+                let v = self.pop();
+                let (layout, r) = self.pop_record()?;
+                let i = self.field_index(layout, k)?;
+                self.heap[r][i] = v;
+            },
         }
-        Some(self.pc + 1)
+        Ok(Some(self.pc + 1))
     }
     
-    pub fn execute(&mut self) {
+    // Run the program to completion, returning its exit status.
+    pub fn execute(&mut self) -> Result<i64, RuntimeError> {
         self.pc = 0;
-        while let Some(new_pc) = self.step() {
+        let result = self.run();
+        // Output from 'write' may still be buffered. Flush it before the caller reports an error
+        // or exits.
+        std::io::stdout().flush().expect("stdout should be writable");
+        result
+    }
+
+    fn run(&mut self) -> Result<i64, RuntimeError> {
+        while let Some(new_pc) = self.step()? {
             self.pc = new_pc;
         }
+        Ok(self.status)
+    }
+
+    // The statement being executed, e.g. the one that caused a runtime error.
+    pub fn location(&self) -> Span {
+        self.spans[self.pc]
     }
 
     pub fn dump_state(&self) {
+        let stack: Vec<String> = self.stack.iter().map(|v| v.to_string()).collect();
         println!("pc = {}", self.pc);
-        println!("stack = {:?}", self.stack);
+        println!("stack = [{}]", stack.join(", "));
     }
 }
-
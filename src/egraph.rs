@@ -49,6 +49,7 @@
 
 use egg::RecExpr;
 use egg::EGraph;
+use egg::{Analysis, DidMerge, Language, Subst};
 use egg::Rewrite;
 use egg::Runner;
 use egg::Extractor;
@@ -56,16 +57,63 @@ use egg::Id;
 use egg::Symbol;
 use egg::{rewrite, define_language};
 
-use crate::syntax::{Block, Stmt, Expr, BinOp, Var, Program};
+use crate::syntax::{Block, Stmt, PrintItem, Expr, BinOp, Builtin, Var, Program, Span};
 use crate::parser::ProgramParser;
-use crate::compiler::{Compiler, VM};
+use crate::compiler::{Compiler, VM, Value, IntMode};
+use crate::typecheck;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+// A string literal, as a leaf of the egraph. It is written with its quotes, to distinguish it from
+// a GraphExpr::Symbol.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+struct StrLit(Symbol);
+
+impl fmt::Display for StrLit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0.as_str())
+    }
+}
+
+impl FromStr for StrLit {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let s = s.strip_prefix('"').and_then(|s| s.strip_suffix('"')).ok_or(())?;
+        Ok(StrLit(Symbol::from(s)))
+    }
+}
+
+// A float constant, as a leaf of the egraph. It is compared by its bits, and always written with
+// a decimal point, to distinguish it from an integer constant.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+struct FloatLit(u64);
+
+impl fmt::Display for FloatLit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", f64::from_bits(self.0))
+    }
+}
+
+impl FromStr for FloatLit {
+    type Err = std::num::ParseFloatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<f64>().map(|x| FloatLit(x.to_bits()))
+    }
+}
 
 define_language! {
     enum GraphExpr {
         // Numeric constants
         Num(i64),
+        Float(FloatLit),
+        // Source locations, which only appear as the last operand of 'assert'.
+        Loc(Span),
+        // String literals, which only appear as the last operand of 'write-str'.
+        Str(StrLit),
         // Abstract symbols. IIUC these would arise from (e.g.) function parameters that don't have
         // a specific value. I don't have functions, but it doesn't hurt anything to leave this
         // constructor here.
@@ -76,23 +124,214 @@ define_language! {
         "+" = Add([Id; 2]),
         "-" = Sub([Id; 2]),
         "*" = Mul([Id; 2]),
+        "/" = Div([Id; 2]),
+        "%" = Mod([Id; 2]),
+        "&" = BitAnd([Id; 2]),
+        "|" = BitOr([Id; 2]),
+        "^" = BitXor([Id; 2]),
+        "<<" = Shl([Id; 2]),
+        ">>" = Shr([Id; 2]),
+        "neg" = Neg(Id),
+        "abs" = Abs(Id),
+        "min" = Min([Id; 2]),
+        "max" = Max([Id; 2]),
+        "pow" = Pow([Id; 2]),
+        "float" = ToFloat(Id),
+        "int" = ToInt(Id),
         "<" = Lt([Id; 2]),
         ">" = Gt([Id; 2]),
+        "==" = Eq([Id; 2]),
+        "!=" = Ne([Id; 2]),
+        "<=" = Le([Id; 2]),
+        ">=" = Ge([Id; 2]),
+
+        // Boolean connectives. '&&' and '||' only evaluate their second operand if needed, so
+        // rewrites must not move a potentially-trapping operand out of second position.
+        "&&" = And([Id; 2]),
+        "||" = Or([Id; 2]),
+        "!" = Not(Id),
+        // 'select c x y' is the conditional expression 'if c then x else y'. Like '&&', only one
+        // of 'x' and 'y' is evaluated.
+        "select" = Select([Id; 3]),
 
         // Read the argument at position <i> from the arguments array.
         "args" = ArgRef(Id),
+        // The number of arguments.
+        "argc" = Argc,
 
         // '[]' is a kind of dummy value, representing "perform no IO operations" (i.e., trivial
         // effect, pure ())
         "[]" = IOInit,
         // 'e1 >>> e2' means "perform operations from e1 and then print e2"
         ">>>" = IOSeq([Id; 2]),
+        // Like '>>>', but without ending the line.
+        "write" = Write([Id; 2]),
+        "write-str" = WriteStr([Id; 2]),
+        "newline" = Newline(Id),
+        // 'assert e1 e2 loc' means "perform operations from e1 and then fail if e2 is zero". It is
+        // chained through the IO root just like a print, so that it is neither dropped nor moved
+        // relative to other effects.
+        "assert" = Assert([Id; 3]),
+        // 'read e' reads an int after performing the operations from e. It is both the int and the
+        // new IO root, so that reads stay in order, and so does 'eof', whose result depends on
+        // them.
+        "read" = Read(Id),
+        "eof" = Eof(Id),
+        // 'eval e1 e2' computes e2 after performing the operations from e1. A variable is bound
+        // this way when its value can fail, so that the failure is neither dropped nor moved
+        // relative to other effects, even if the variable is never used.
+        "eval" = Eval([Id; 2]),
+    }
+}
+
+// Whether an e-class is known to hold ints or floats. Ints include booleans.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Kind {
+    Unknown,
+    Int,
+    Float,
+}
+
+// What is known about the value of an e-class.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Facts {
+    // The value is either 0 or 1.
+    boolean: bool,
+    kind: Kind,
+    // Evaluating it never fails with a runtime error, so a rewrite may drop it.
+    safe: bool,
+}
+
+// Recovers what the type checker knows about the program's values. It guarantees that every
+// bool-typed expression is built from comparisons, connectives, and the literals 0 and 1, and
+// that both operands of an arithmetic operator have the same type, so that a single int or float
+// operand determines the type of the other. Inputs may be either, so their kind is unknown unless
+// something else in their class says otherwise.
+//
+// It also finds the values whose evaluation cannot fail. The rules never equate an expression
+// that can fail with one that cannot, so one node of a class that is known to be safe is enough.
+// Division and shifts are only safe with a literal operand that is known to be valid, and inputs
+// never are, as there may be too few of them. When overflow traps, no int arithmetic that could
+// overflow is safe either.
+struct TypeFacts {
+    mode: IntMode,
+}
+
+impl Analysis<GraphExpr> for TypeFacts {
+    type Data = Facts;
+
+    fn make(egraph: &EGraph<GraphExpr, Self>, enode: &GraphExpr) -> Facts {
+        let is_bool = |x: &Id| egraph[*x].data.boolean;
+        let kind = |x: &Id| egraph[*x].data.kind;
+        let either = |x: &Id, y: &Id| if kind(x) == Kind::Unknown { kind(y) } else { kind(x) };
+        let boolean = match enode {
+            GraphExpr::Num(n) => *n == 0 || *n == 1,
+            GraphExpr::Lt(_) | GraphExpr::Gt(_) | GraphExpr::Eq(_) | GraphExpr::Ne(_) |
+            GraphExpr::Le(_) | GraphExpr::Ge(_) => true,
+            GraphExpr::And(_) | GraphExpr::Or(_) | GraphExpr::Not(_) | GraphExpr::Eof(_) => true,
+            GraphExpr::Select([_c, x, y]) => is_bool(x) && is_bool(y),
+            GraphExpr::BitAnd([x, y]) => is_bool(x) || is_bool(y),
+            GraphExpr::BitOr([x, y]) | GraphExpr::BitXor([x, y]) | GraphExpr::Mul([x, y]) |
+            GraphExpr::Min([x, y]) | GraphExpr::Max([x, y]) => {
+                is_bool(x) && is_bool(y)
+            },
+            // 0 and 1 are their own absolute values, and every power of them is 0 or 1.
+            GraphExpr::Abs(x) | GraphExpr::Pow([x, _]) => is_bool(x),
+            _ => false,
+        };
+        let literal = |x: &Id| egraph[*x].nodes.iter().find_map(|node| match node {
+            GraphExpr::Num(n) => Some(*n),
+            _ => None,
+        });
+        // Whether an int result that does not fit in 64 bits would be an error.
+        let traps = |x: &Id, y: &Id| egraph.analysis.mode == IntMode::Trap && either(x, y) != Kind::Float;
+        let safe = match enode {
+            // Only 'i64::MIN / -1' overflows.
+            GraphExpr::Div([x, y]) if traps(x, y) && literal(y).is_none_or(|n| n == -1) => false,
+            GraphExpr::Add([x, y]) | GraphExpr::Sub([x, y]) | GraphExpr::Mul([x, y]) |
+            GraphExpr::Shl([x, y]) | GraphExpr::Pow([x, y]) if traps(x, y) => false,
+            GraphExpr::Neg(x) | GraphExpr::Abs(x) if traps(x, x) => false,
+            GraphExpr::ToInt(_) if egraph.analysis.mode == IntMode::Trap => false,
+            GraphExpr::Div([x, y]) | GraphExpr::Mod([x, y]) => {
+                let divisor = either(x, y) == Kind::Float || literal(y).is_some_and(|n| n != 0);
+                egraph[*x].data.safe && egraph[*y].data.safe && divisor
+            },
+            GraphExpr::Shl([x, y]) | GraphExpr::Shr([x, y]) => {
+                egraph[*x].data.safe && literal(y).is_some_and(|n| (0..64).contains(&n))
+            },
+            GraphExpr::Pow([x, y]) => {
+                let exponent = either(x, y) == Kind::Float || literal(y).is_some_and(|n| n >= 0);
+                egraph[*x].data.safe && egraph[*y].data.safe && exponent
+            },
+            GraphExpr::ArgRef(_) => false,
+            // These only read the result of an IO operation, which is performed whether or not
+            // they are used.
+            GraphExpr::Read(_) | GraphExpr::Eof(_) => true,
+            _ => enode.all(|x| egraph[x].data.safe),
+        };
+        let kind = match enode {
+            GraphExpr::Float(_) | GraphExpr::ToFloat(_) => Kind::Float,
+            GraphExpr::Add([x, y]) | GraphExpr::Sub([x, y]) | GraphExpr::Mul([x, y]) |
+            GraphExpr::Div([x, y]) | GraphExpr::Mod([x, y]) => either(x, y),
+            GraphExpr::Min([x, y]) | GraphExpr::Max([x, y]) | GraphExpr::Pow([x, y]) => either(x, y),
+            GraphExpr::Abs(x) => kind(x),
+            GraphExpr::Select([_c, x, y]) => either(x, y),
+            GraphExpr::Neg(x) => kind(x),
+            GraphExpr::ArgRef(_) => Kind::Unknown,
+            // Everything else that has a value is an int.
+            _ => Kind::Int,
+        };
+        Facts { boolean, kind, safe }
+    }
+
+    // Equal values have the same type, and are either both booleans or both not, so whatever is
+    // known about one node of a class holds for all of them.
+    fn merge(&mut self, a: &mut Facts, b: Facts) -> DidMerge {
+        let merged = Facts {
+            boolean: a.boolean || b.boolean,
+            kind: if a.kind == Kind::Unknown { b.kind } else { a.kind },
+            safe: a.safe || b.safe,
+        };
+        let did_merge = DidMerge(merged != *a, merged != b);
+        *a = merged;
+        did_merge
+    }
+}
+
+fn is_bool(var: &str) -> impl Fn(&mut EGraph<GraphExpr, TypeFacts>, Id, &Subst) -> bool {
+    let var: egg::Var = var.parse().unwrap();
+    move |egraph, _, subst| egraph[subst[var]].data.boolean
+}
+
+// A rule that drops an operand must not drop a runtime error along with it.
+fn is_safe(var: &str) -> impl Fn(&mut EGraph<GraphExpr, TypeFacts>, Id, &Subst) -> bool {
+    let var: egg::Var = var.parse().unwrap();
+    move |egraph, _, subst| egraph[subst[var]].data.safe
+}
+
+// Many identities hold for integers, but not for floats, e.g. because of NaN or rounding. The
+// variables are operands of the same operator, so they all have the same type, and it is enough
+// for one of them to be known to be an int.
+fn is_int(vars: &[&str]) -> impl Fn(&mut EGraph<GraphExpr, TypeFacts>, Id, &Subst) -> bool {
+    let vars: Vec<egg::Var> = vars.iter().map(|v| v.parse().unwrap()).collect();
+    move |egraph, _, subst| vars.iter().any(|v| egraph[subst[*v]].data.kind == Kind::Int)
+}
+
+// A feature that the translation to an egraph does not handle yet. A program that uses one is run
+// without being optimized.
+#[derive(Debug)]
+struct Unsupported(&'static str);
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} are not supported by the optimizer", self.0)
     }
 }
 
 struct EGraphBuilder {
-    env: HashMap<Var, Id>,
-    graph: EGraph<GraphExpr, ()>,
+    // The value of each variable in scope, one map per enclosing block, innermost last.
+    env: Vec<HashMap<Var, Id>>,
+    graph: EGraph<GraphExpr, TypeFacts>,
     // The "IO Root" is intended to sequence side-effecting operations by having each new 'print'
     // statement take a reference to the current IO Root, then updating the root to point to the
     // new 'print'.
@@ -100,51 +339,148 @@ struct EGraphBuilder {
 }
 
 impl EGraphBuilder {
-    fn new() -> Self {
-        let mut graph = EGraph::new(());
+    fn new(mode: IntMode) -> Self {
+        let mut graph = EGraph::new(TypeFacts { mode });
         let io_root = graph.add(GraphExpr::IOInit);
 
         EGraphBuilder {
-            env: HashMap::new(),
-            graph: graph,
-            io_root: io_root
+            env: Vec::new(),
+            graph,
+            io_root,
         }
     }
 
-    fn expression_to_egraph(&mut self, e: &Expr) -> Id {
-        match e {
+    fn expression_to_egraph(&mut self, e: &Expr) -> Result<Id, Unsupported> {
+        let id = match e {
             Expr::Var(x) => {
-                // lookup var in map of var -> id
-                *self.env.get(x).unwrap()
+                // lookup var in map of var -> id, starting from the innermost scope
+                self.scope_of(x)?[x]
             },
             Expr::Num(i) => {
                 // emit constant node, return its id
                 self.graph.add(GraphExpr::Num(*i))
             },
+            Expr::Float(x) => self.graph.add(GraphExpr::Float(FloatLit(x.to_bits()))),
+            Expr::Bool(b) => {
+                // booleans are represented as 0 and 1
+                self.graph.add(GraphExpr::Num(*b as i64))
+            },
             Expr::BinOp(op, e1, e2) => {
-                let i1 = self.expression_to_egraph(e1);
-                let i2 = self.expression_to_egraph(e2);
+                let i1 = self.expression_to_egraph(e1)?;
+                let i2 = self.expression_to_egraph(e2)?;
                 // add binop(i1, i2) to egraph
                 match op {
                     BinOp::Add => self.graph.add(GraphExpr::Add([i1, i2])),
                     BinOp::Sub => self.graph.add(GraphExpr::Sub([i1, i2])),
                     BinOp::Mul => self.graph.add(GraphExpr::Mul([i1, i2])),
+                    BinOp::Div => self.graph.add(GraphExpr::Div([i1, i2])),
+                    BinOp::Mod => self.graph.add(GraphExpr::Mod([i1, i2])),
+                    BinOp::BitAnd => self.graph.add(GraphExpr::BitAnd([i1, i2])),
+                    BinOp::BitOr => self.graph.add(GraphExpr::BitOr([i1, i2])),
+                    BinOp::BitXor => self.graph.add(GraphExpr::BitXor([i1, i2])),
+                    BinOp::Shl => self.graph.add(GraphExpr::Shl([i1, i2])),
+                    BinOp::Shr => self.graph.add(GraphExpr::Shr([i1, i2])),
                     BinOp::Lt => self.graph.add(GraphExpr::Lt([i1, i2])),
                     BinOp::Gt => self.graph.add(GraphExpr::Gt([i1, i2])),
+                    BinOp::Eq => self.graph.add(GraphExpr::Eq([i1, i2])),
+                    BinOp::Ne => self.graph.add(GraphExpr::Ne([i1, i2])),
+                    BinOp::Le => self.graph.add(GraphExpr::Le([i1, i2])),
+                    BinOp::Ge => self.graph.add(GraphExpr::Ge([i1, i2])),
                 }
             },
+            Expr::Neg(e) => {
+                let i = self.expression_to_egraph(e)?;
+                self.graph.add(GraphExpr::Neg(i))
+            },
             Expr::Input(e) => {
-                let i = self.expression_to_egraph(e);
+                let i = self.expression_to_egraph(e)?;
                 self.graph.add(GraphExpr::ArgRef(i))
             },
+            Expr::Call(_f, _args) => {
+                // Procedures may perform IO, so a call is not a pure node.
+                return Err(Unsupported("procedure calls"))
+            },
+            Expr::Builtin(b, args) => {
+                let ids = args.iter().map(|arg| self.expression_to_egraph(arg)).collect::<Result<Vec<Id>, _>>()?;
+                match (b, ids.as_slice()) {
+                    (Builtin::Argc, []) => self.graph.add(GraphExpr::Argc),
+                    (Builtin::Eof, []) => {
+                        self.io_root = self.graph.add(GraphExpr::Eof(self.io_root));
+                        self.io_root
+                    },
+                    (Builtin::Abs, [x]) => self.graph.add(GraphExpr::Abs(*x)),
+                    (Builtin::Min, [x, y]) => self.graph.add(GraphExpr::Min([*x, *y])),
+                    (Builtin::Max, [x, y]) => self.graph.add(GraphExpr::Max([*x, *y])),
+                    (Builtin::Pow, [x, y]) => self.graph.add(GraphExpr::Pow([*x, *y])),
+                    // The compiler has already checked the number of arguments.
+                    _ => unreachable!("wrong number of arguments to {}", b.name()),
+                }
+            },
+            Expr::And(e1, e2) => {
+                let i1 = self.expression_to_egraph(e1)?;
+                let i2 = self.expression_to_egraph(e2)?;
+                self.graph.add(GraphExpr::And([i1, i2]))
+            },
+            Expr::Or(e1, e2) => {
+                let i1 = self.expression_to_egraph(e1)?;
+                let i2 = self.expression_to_egraph(e2)?;
+                self.graph.add(GraphExpr::Or([i1, i2]))
+            },
+            Expr::Not(e) => {
+                let i = self.expression_to_egraph(e)?;
+                self.graph.add(GraphExpr::Not(i))
+            },
+            Expr::ToFloat(e) => {
+                let i = self.expression_to_egraph(e)?;
+                self.graph.add(GraphExpr::ToFloat(i))
+            },
+            Expr::ToInt(e) => {
+                let i = self.expression_to_egraph(e)?;
+                self.graph.add(GraphExpr::ToInt(i))
+            },
+            Expr::If(c, e1, e2) => {
+                let ic = self.expression_to_egraph(c)?;
+                let i1 = self.expression_to_egraph(e1)?;
+                let i2 = self.expression_to_egraph(e2)?;
+                self.graph.add(GraphExpr::Select([ic, i1, i2]))
+            },
+            Expr::NewArray(_, _) | Expr::Index(_, _) | Expr::Len(_) => {
+                // Arrays are mutable, so reads would need to be sequenced with stores.
+                return Err(Unsupported("arrays"))
+            },
+            Expr::Record(_, _) | Expr::Field(_, _) => {
+                // Records are mutable too.
+                return Err(Unsupported("records"))
+            },
+        };
+        Ok(id)
+    }
+
+    // The innermost scope that declares x. The compiler rejects a program that uses an undeclared
+    // variable, but the optimizer may be asked about such a program anyway.
+    fn scope_of(&mut self, x: &Var) -> Result<&mut HashMap<Var, Id>, Unsupported> {
+        self.env.iter_mut().rev().find(|scope| scope.contains_key(x)).ok_or(Unsupported("undeclared variables"))
+    }
+
+    // A value that can fail is computed where the program computes it.
+    fn evaluate(&mut self, id: Id) {
+        if !self.graph[id].data.safe {
+            self.io_root = self.graph.add(GraphExpr::Eval([self.io_root, id]));
         }
     }
 
-    fn block_to_egraph(&mut self, block: &Block) {
-        for s in &block.0 {
+    fn block_to_egraph(&mut self, block: &Block) -> Result<(), Unsupported> {
+        self.env.push(HashMap::new());
+        for (_span, s) in &block.0 {
             match s {
+                Stmt::Let(x, e) => {
+                    let id = self.expression_to_egraph(e)?;
+                    self.evaluate(id);
+                    self.env.last_mut().unwrap().insert(x.clone(), id);
+                },
                 Stmt::Assign(x, e) => {
-                    let id = self.expression_to_egraph(e);
+                    let id = self.expression_to_egraph(e)?;
+                    self.evaluate(id);
                     // add x -> id to env
                     // make sure that I deal with reassignments properly.
                     // I *think* what should happen is that each assignment of a variable creates a
@@ -152,24 +488,73 @@ impl EGraphBuilder {
                     //
                     // The tricky part is how I merge things back together. I don't know how to
                     // represent a phi-function in an egraph
-                    self.env.insert(x.clone(), id);
+                    //
+                    self.scope_of(x)?.insert(x.clone(), id);
 
                     // Actually, what's GraphExpr::Symbol for? opaque variables that don't have a
                     // know definition? (e.g., function parameters or user input?)
                 },
-                Stmt::Print(e) => {
-                    let id = self.expression_to_egraph(e);
-                    self.io_root = self.graph.add(GraphExpr::IOSeq([self.io_root, id]));
+                Stmt::Read(x) => {
+                    self.io_root = self.graph.add(GraphExpr::Read(self.io_root));
+                    let io_root = self.io_root;
+                    self.scope_of(x)?.insert(x.clone(), io_root);
+                },
+                Stmt::Print(items, newline) => {
+                    // This mirrors the bytecode: a trailing value is printed along with the
+                    // newline, using '>>>'.
+                    let mut newline = *newline;
+                    for (i, item) in items.iter().enumerate() {
+                        let io = match item {
+                            PrintItem::Str(s) => {
+                                let str_id = self.graph.add(GraphExpr::Str(StrLit(Symbol::from(s))));
+                                GraphExpr::WriteStr([self.io_root, str_id])
+                            },
+                            PrintItem::Expr(e) => {
+                                let id = self.expression_to_egraph(e)?;
+                                if newline && i == items.len() - 1 {
+                                    newline = false;
+                                    GraphExpr::IOSeq([self.io_root, id])
+                                } else {
+                                    GraphExpr::Write([self.io_root, id])
+                                }
+                            },
+                        };
+                        self.io_root = self.graph.add(io);
+                    }
+                    if newline {
+                        self.io_root = self.graph.add(GraphExpr::Newline(self.io_root));
+                    }
                 },
+                Stmt::Assert(e, span) => {
+                    let id = self.expression_to_egraph(e)?;
+                    let loc = self.graph.add(GraphExpr::Loc(*span));
+                    self.io_root = self.graph.add(GraphExpr::Assert([self.io_root, id, loc]));
+                },
+                Stmt::Call(..) | Stmt::Return(..) => return Err(Unsupported("procedure calls")),
+                Stmt::Store(..) => return Err(Unsupported("arrays")),
+                Stmt::SetField(..) => return Err(Unsupported("records")),
                 _ => {
-                    unimplemented!("complex statements to egraph")
+                    return Err(Unsupported("control flow statements"))
                 },
             }
         }
+        self.env.pop();
+        Ok(())
     }
 
-    fn program_to_egraph(&mut self, prog: &Program) {
-        self.block_to_egraph(&prog.0)
+    fn program_to_egraph(&mut self, prog: &Program) -> Result<(), Unsupported> {
+        // Constants are just names for their initializers, which the optimized program inlines.
+        let mut consts = HashMap::new();
+        for c in &prog.consts {
+            self.env.push(consts);
+            let id = self.expression_to_egraph(&c.value)?;
+            consts = self.env.pop().unwrap();
+            consts.insert(c.name.clone(), id);
+        }
+        self.env.push(consts);
+        self.block_to_egraph(&prog.body)?;
+        self.env.pop();
+        Ok(())
     }
 
 }
@@ -188,167 +573,455 @@ fn extract_program(exprs: &RecExpr<GraphExpr>) -> Block {
     // constant easily)
 
     let mut num_uses = HashMap::new();
-    let mut count = |x: &Id| {
-        *num_uses.entry(x.clone()).or_insert(0) += 1;
-    };
     for ge in expr_slice {
-        match ge {
-            GraphExpr::Add([x, y]) => { count(x); count(y); },
-            GraphExpr::Mul([x, y]) => { count(x); count(y); },
-            GraphExpr::Sub([x, y]) => { count(x); count(y); },
-            GraphExpr::Lt([x, y]) => { count(x); count(y); },
-            GraphExpr::Gt([x, y]) => { count(x); count(y); },
-            GraphExpr::ArgRef(x) => { count(x); },
-            GraphExpr::IOSeq([x, y]) => { count(x); count(y); },
-            _ => {}, // Num, Symbol, IOInit
+        for x in ge.children() {
+            *num_uses.entry(*x).or_insert(0) += 1;
         }
     }
     // println!("{:?}", num_uses);
 
-
-    let mut builder = ExprBuilder::new(num_uses);
-    for (i, ge) in expr_slice.iter().enumerate() {
-        let exp = match ge {
-            GraphExpr::Add([x, y]) => {
-                let ex = builder.get_exp(x);
-                let ey = builder.get_exp(y);
-                Expr::BinOp(BinOp::Add, Box::new(ex), Box::new(ey))
-            },
-            GraphExpr::Mul([x, y]) => {
-                let ex = builder.get_exp(x);
-                let ey = builder.get_exp(y);
-                Expr::BinOp(BinOp::Mul, Box::new(ex), Box::new(ey))
-            },
-            GraphExpr::Num(n) => {
-                Expr::Num(*n)
-            },
-            GraphExpr::ArgRef(x) => {
-                let ex = builder.get_exp(x);
-                Expr::Input(Box::new(ex))
-            },
-            GraphExpr::IOInit => {
-                Expr::Num(0) // just a dummy value. not actually used for anything other than being
-                             // consumed by IOSeq
-            },
-            GraphExpr::IOSeq([x, y]) => {
-                let _ex = builder.get_exp(x); // this value is a dummy 0. discard it.
-                let ey = builder.get_exp(y);
-                builder.print(ey);
-                Expr::Num(0) // just a dummy value
-            },
-            _ => unimplemented!("lol"),
+    // The order of the nodes only respects their dependencies, so it may put a value before an IO
+    // operation that comes earlier in the program. Instead, the IO operations are followed back
+    // from the root, and then performed in order, each building the expressions that it uses.
+    let mut chain = Vec::new();
+    let mut io = Id::from(expr_slice.len() - 1);
+    loop {
+        chain.push(io);
+        io = match &expr_slice[usize::from(io)] {
+            GraphExpr::IOInit => break,
+            GraphExpr::IOSeq([x, _]) | GraphExpr::Write([x, _]) | GraphExpr::WriteStr([x, _]) |
+            GraphExpr::Eval([x, _]) | GraphExpr::Assert([x, _, _]) => *x,
+            GraphExpr::Newline(x) | GraphExpr::Read(x) | GraphExpr::Eof(x) => *x,
+            ge => unreachable!("{:?} is not an IO operation", ge),
         };
+    }
 
-        let id = Id::from(i);
-        builder.add_exp(id, exp);
+    let mut builder = ExprBuilder::new(expr_slice, num_uses);
+    for io in chain.into_iter().rev() {
+        builder.perform(io);
     }
 
-    Block(builder.stmts)
+    // The optimized program has no source text for its statements to point into.
+    let no_span = Span { start: 0, end: 0 };
+    Block(builder.stmts.into_iter().map(|s| (no_span, s)).collect())
 }
 
-struct ExprBuilder {
+struct ExprBuilder<'a> {
+    nodes: &'a [GraphExpr],
     // TODO: Avoid assigning constants to local variables. They can just be duplicated at their use
     // sites, because a constant/literal in the program is cheaper than a locals slot+retrieval.
     vars: HashMap<Id, Var>,
-    exps: HashMap<Id, Expr>,
+    // The nodes that are used more than once. Each is saved in a variable where it is first used,
+    // unless that use is only evaluated conditionally.
+    shared: HashSet<Id>,
+    // How many operands that may be skipped enclose the expression being built.
+    conditional: usize,
     stmts: Vec<Stmt>,
 }
 
-impl ExprBuilder {
-    fn new(num_uses: HashMap<Id, usize>) -> Self {
-        let mut vars = HashMap::new();
-        for (k, v) in num_uses.into_iter() {
-            if v > 1 {
-                vars.insert(k, Var(format!("x{}", k)));
-            }
+impl<'a> ExprBuilder<'a> {
+    fn new(nodes: &'a [GraphExpr], num_uses: HashMap<Id, usize>) -> Self {
+        let shared = num_uses.into_iter().filter(|(_, n)| *n > 1).map(|(k, _)| k).collect();
+        ExprBuilder { nodes, vars: HashMap::new(), shared, conditional: 0, stmts: Vec::new() }
+    }
+
+    fn perform(&mut self, io: Id) {
+        match &self.nodes[usize::from(io)] {
+            GraphExpr::IOInit => {},
+            GraphExpr::IOSeq([_, x]) => {
+                let ex = self.get_exp(x);
+                self.print(ex);
+            },
+            GraphExpr::Write([_, x]) => {
+                let ex = self.get_exp(x);
+                self.write(PrintItem::Expr(Box::new(ex)), false);
+            },
+            GraphExpr::WriteStr([_, s]) => {
+                let s = match &self.nodes[usize::from(*s)] {
+                    GraphExpr::Str(StrLit(s)) => s.as_str().to_string(),
+                    _ => unreachable!("write-str without a string"),
+                };
+                self.write(PrintItem::Str(s), false);
+            },
+            GraphExpr::Newline(_) => self.newline(),
+            GraphExpr::Assert([_, x, loc]) => {
+                let ex = self.get_exp(x);
+                let span = match &self.nodes[usize::from(*loc)] {
+                    GraphExpr::Loc(span) => *span,
+                    _ => unreachable!("assert without a source location"),
+                };
+                self.assert(ex, span);
+            },
+            // These are statements, which must stay at their place in the sequence of IO
+            // operations, so their values are always kept in variables.
+            GraphExpr::Read(_) => self.read(io),
+            GraphExpr::Eof(_) => {
+                self.bind(io, Expr::Builtin(Builtin::Eof, vec![]));
+            },
+            GraphExpr::Eval([_, x]) => {
+                if !self.vars.contains_key(x) {
+                    let ex = self.build(*x);
+                    self.bind(*x, ex);
+                }
+            },
+            ge => unreachable!("{:?} is not an IO operation", ge),
         }
-        ExprBuilder { vars, exps: HashMap::new(), stmts: Vec::new() }
     }
 
     fn get_exp(&mut self, x: &Id) -> Expr {
-        match self.vars.get(x) {
-            None => self.exps.remove(x).unwrap(),
-            Some(v) => Expr::Var(v.clone()),
+        if let Some(v) = self.vars.get(x) {
+            return Expr::Var(v.clone());
         }
+        let ex = self.build(*x);
+        // Saving the value before the statement would compute it even when the operand that uses
+        // it is skipped, which would be wrong if computing it fails. So it is duplicated instead.
+        if self.shared.contains(x) && self.conditional == 0 {
+            Expr::Var(self.bind(*x, ex))
+        } else {
+            ex
+        }
+    }
+
+    // The expression for an operand that is not always evaluated.
+    fn get_conditional_exp(&mut self, x: &Id) -> Expr {
+        self.conditional += 1;
+        let ex = self.get_exp(x);
+        self.conditional -= 1;
+        ex
     }
 
-    fn add_exp(&mut self, x: Id, e: Expr) {
-        match self.vars.get(&x) {
-            Some(v) => {
-                self.stmts.push(Stmt::Assign(v.clone(), Box::new(e)))
+    // The expression for a node that has not been saved in a variable.
+    fn build(&mut self, x: Id) -> Expr {
+        match &self.nodes[usize::from(x)] {
+            GraphExpr::Add([x, y]) => self.binop(BinOp::Add, x, y),
+            GraphExpr::Sub([x, y]) => self.binop(BinOp::Sub, x, y),
+            GraphExpr::Mul([x, y]) => self.binop(BinOp::Mul, x, y),
+            GraphExpr::Div([x, y]) => self.binop(BinOp::Div, x, y),
+            GraphExpr::Mod([x, y]) => self.binop(BinOp::Mod, x, y),
+            GraphExpr::BitAnd([x, y]) => self.binop(BinOp::BitAnd, x, y),
+            GraphExpr::BitOr([x, y]) => self.binop(BinOp::BitOr, x, y),
+            GraphExpr::BitXor([x, y]) => self.binop(BinOp::BitXor, x, y),
+            GraphExpr::Shl([x, y]) => self.binop(BinOp::Shl, x, y),
+            GraphExpr::Shr([x, y]) => self.binop(BinOp::Shr, x, y),
+            GraphExpr::Neg(x) => {
+                let ex = self.get_exp(x);
+                Expr::Neg(Box::new(ex))
+            },
+            GraphExpr::Lt([x, y]) => self.binop(BinOp::Lt, x, y),
+            GraphExpr::Gt([x, y]) => self.binop(BinOp::Gt, x, y),
+            GraphExpr::Eq([x, y]) => self.binop(BinOp::Eq, x, y),
+            GraphExpr::Ne([x, y]) => self.binop(BinOp::Ne, x, y),
+            GraphExpr::Le([x, y]) => self.binop(BinOp::Le, x, y),
+            GraphExpr::Ge([x, y]) => self.binop(BinOp::Ge, x, y),
+            GraphExpr::And([x, y]) => {
+                let ex = self.get_exp(x);
+                let ey = self.get_conditional_exp(y);
+                Expr::And(Box::new(ex), Box::new(ey))
+            },
+            GraphExpr::Or([x, y]) => {
+                let ex = self.get_exp(x);
+                let ey = self.get_conditional_exp(y);
+                Expr::Or(Box::new(ex), Box::new(ey))
             },
-            None => {
-                self.exps.insert(x, e);
+            GraphExpr::Not(x) => {
+                let ex = self.get_exp(x);
+                Expr::Not(Box::new(ex))
             },
+            GraphExpr::ToFloat(x) => {
+                let ex = self.get_exp(x);
+                Expr::ToFloat(Box::new(ex))
+            },
+            GraphExpr::ToInt(x) => {
+                let ex = self.get_exp(x);
+                Expr::ToInt(Box::new(ex))
+            },
+            GraphExpr::Select([c, x, y]) => {
+                let ec = self.get_exp(c);
+                let ex = self.get_conditional_exp(x);
+                let ey = self.get_conditional_exp(y);
+                Expr::If(Box::new(ec), Box::new(ex), Box::new(ey))
+            },
+            GraphExpr::Num(n) => {
+                Expr::Num(*n)
+            },
+            GraphExpr::Float(FloatLit(bits)) => {
+                Expr::Float(f64::from_bits(*bits))
+            },
+            GraphExpr::ArgRef(x) => {
+                let ex = self.get_exp(x);
+                Expr::Input(Box::new(ex))
+            },
+            GraphExpr::Argc => Expr::Builtin(Builtin::Argc, vec![]),
+            GraphExpr::Abs(x) => {
+                let ex = self.get_exp(x);
+                Expr::Builtin(Builtin::Abs, vec![ex])
+            },
+            GraphExpr::Min([x, y]) => self.builtin(Builtin::Min, x, y),
+            GraphExpr::Max([x, y]) => self.builtin(Builtin::Max, x, y),
+            GraphExpr::Pow([x, y]) => self.builtin(Builtin::Pow, x, y),
+            // The value of an IO operation is saved in a variable when it is performed, which is
+            // always before the value is used.
+            ge => unreachable!("{:?} is used before it is performed", ge),
         }
     }
 
+    fn binop(&mut self, op: BinOp, x: &Id, y: &Id) -> Expr {
+        let ex = self.get_exp(x);
+        let ey = self.get_exp(y);
+        Expr::BinOp(op, Box::new(ex), Box::new(ey))
+    }
+
+    fn builtin(&mut self, b: Builtin, x: &Id, y: &Id) -> Expr {
+        let ex = self.get_exp(x);
+        let ey = self.get_exp(y);
+        Expr::Builtin(b, vec![ex, ey])
+    }
+
     fn print(&mut self, e: Expr) {
-        self.stmts.push(Stmt::Print(Box::new(e)));
+        self.write(PrintItem::Expr(Box::new(e)), true);
     }
-}
 
-// TODO: Split a program into a tree of basic blocks, so that I can optimize each block
-// individually.
-fn optimize(prog: &Program) -> Program {
-    let mut com = EGraphBuilder::new();
-    com.program_to_egraph(&prog);
+    // Consecutive pieces of output are merged back into a single print statement, until one of
+    // them ends the line.
+    fn write(&mut self, item: PrintItem, newline: bool) {
+        match self.stmts.last_mut() {
+            Some(Stmt::Print(items, nl @ false)) => {
+                items.push(item);
+                *nl = newline;
+            },
+            _ => self.stmts.push(Stmt::Print(vec![item], newline)),
+        }
+    }
+
+    fn newline(&mut self) {
+        match self.stmts.last_mut() {
+            Some(Stmt::Print(_items, nl @ false)) => *nl = true,
+            _ => self.stmts.push(Stmt::Print(vec![], true)),
+        }
+    }
 
-    let rules: &[Rewrite<GraphExpr, ()>] = &[
+    fn assert(&mut self, e: Expr, span: Span) {
+        self.stmts.push(Stmt::Assert(Box::new(e), span));
+    }
+
+    // Save the value of x in a variable now, whether or not it is used more than once.
+    fn bind(&mut self, x: Id, e: Expr) -> Var {
+        let v = self.vars.entry(x).or_insert_with(|| Var(format!("x{}", x))).clone();
+        self.stmts.push(Stmt::Let(v.clone(), Box::new(e)));
+        v
+    }
+
+    fn read(&mut self, x: Id) {
+        let v = self.bind(x, Expr::Num(0));
+        self.stmts.push(Stmt::Read(v));
+    }
+}
+
+// The rules that are sound under the given overflow behavior. Multiplying by a power of two
+// overflows exactly when the equivalent shift does, so strength reduction is always sound. Rules
+// that drop an operand are guarded by 'is_safe', which takes the mode into account, so they do
+// not drop an overflow that would trap.
+fn rules(mode: IntMode) -> Vec<Rewrite<GraphExpr, TypeFacts>> {
+    let mut rules = vec![
         rewrite!("add-comm"; "(+ ?x ?y)" => "(+ ?y ?x)"),
         rewrite!("mult-comm"; "(* ?x ?y)" => "(* ?y ?x)"),
         rewrite!("add-0"; "(+ ?x 0)" => "?x"),
         rewrite!("mul-1"; "(* ?x 1)" => "?x"),
-        rewrite!("sub-self"; "(- ?x ?x)" => "0"),
-        rewrite!("mult-dist-add"; "(* ?a (+ ?b ?c))" => "(+ (* ?a ?b) (* ?a ?c))"),
+        rewrite!("sub-self"; "(- ?x ?x)" => "0" if is_int(&["?x"]) if is_safe("?x")),
+        // Division by zero is a runtime error, so rules such as 'x / x => 1' or '0 / x => 0'
+        // would be unsound: they could remove a trap. So could dropping a dividend that fails.
+        rewrite!("div-1"; "(/ ?x 1)" => "?x"),
+        rewrite!("mod-1"; "(% ?x 1)" => "0" if is_safe("?x")),
+
+        // Comparisons evaluate to 0 or 1. NaN is not equal to itself, nor ordered with respect
+        // to anything.
+        rewrite!("eq-comm"; "(== ?x ?y)" => "(== ?y ?x)"),
+        rewrite!("ne-comm"; "(!= ?x ?y)" => "(!= ?y ?x)"),
+        rewrite!("eq-self"; "(== ?x ?x)" => "1" if is_int(&["?x"]) if is_safe("?x")),
+        rewrite!("ne-self"; "(!= ?x ?x)" => "0" if is_int(&["?x"]) if is_safe("?x")),
+        rewrite!("lt-self"; "(< ?x ?x)" => "0" if is_safe("?x")),
+        rewrite!("gt-self"; "(> ?x ?x)" => "0" if is_safe("?x")),
+        rewrite!("le-self"; "(<= ?x ?x)" => "1" if is_int(&["?x"]) if is_safe("?x")),
+        rewrite!("ge-self"; "(>= ?x ?x)" => "1" if is_int(&["?x"]) if is_safe("?x")),
     ];
+    rules.extend(rewrite!("lt-flip"; "(< ?x ?y)" <=> "(> ?y ?x)"));
+    rules.extend(rewrite!("le-flip"; "(<= ?x ?y)" <=> "(>= ?y ?x)"));
+    // The literal 0 is an int, so '0 - x' cannot be used to negate a float.
+    rules.push(rewrite!("sub-neg"; "(- 0 ?x)" => "(neg ?x)"));
+    rules.push(rewrite!("sub-neg-rev"; "(neg ?x)" => "(- 0 ?x)" if is_int(&["?x"])));
+
+    // Bitwise operators. Shifting by an amount outside 0..64 traps, so shifts are only ever
+    // introduced or removed with a constant amount. As with 'x - x', an operand that is dropped
+    // must not be able to fail.
+    rules.extend(vec![
+        rewrite!("bitand-comm"; "(& ?x ?y)" => "(& ?y ?x)"),
+        rewrite!("bitor-comm"; "(| ?x ?y)" => "(| ?y ?x)"),
+        rewrite!("bitxor-comm"; "(^ ?x ?y)" => "(^ ?y ?x)"),
+        rewrite!("bitand-0"; "(& ?x 0)" => "0" if is_safe("?x")),
+        rewrite!("bitand-ones"; "(& ?x -1)" => "?x"),
+        rewrite!("bitand-self"; "(& ?x ?x)" => "?x"),
+        rewrite!("bitor-0"; "(| ?x 0)" => "?x"),
+        rewrite!("bitor-ones"; "(| ?x -1)" => "-1" if is_safe("?x")),
+        rewrite!("bitor-self"; "(| ?x ?x)" => "?x"),
+        rewrite!("bitxor-0"; "(^ ?x 0)" => "?x"),
+        rewrite!("bitxor-self"; "(^ ?x ?x)" => "0" if is_safe("?x")),
+        rewrite!("shl-0"; "(<< ?x 0)" => "?x"),
+        rewrite!("shr-0"; "(>> ?x 0)" => "?x"),
+    ]);
+    // Strength reduction. Note that '% 2' and '& 1' differ for negative numbers, so there is no
+    // such rule for parity.
+    rules.extend(rewrite!("mul-2-shl"; "(* ?x 2)" <=> "(<< ?x 1)"));
+    rules.extend(rewrite!("mul-4-shl"; "(* ?x 4)" <=> "(<< ?x 2)"));
+    rules.extend(rewrite!("mul-8-shl"; "(* ?x 8)" <=> "(<< ?x 3)"));
+
+    // Negating a comparison, expressed arithmetically as '1 - c'. Negating an ordering is only
+    // valid for ints, because every comparison with NaN is false.
+    rules.extend(rewrite!("one-minus-lt"; "(- 1 (< ?x ?y))" <=> "(>= ?x ?y)" if is_int(&["?x", "?y"])));
+    rules.extend(rewrite!("one-minus-gt"; "(- 1 (> ?x ?y))" <=> "(<= ?x ?y)" if is_int(&["?x", "?y"])));
+    rules.extend(rewrite!("one-minus-eq"; "(- 1 (== ?x ?y))" <=> "(!= ?x ?y)"));
+    // ... and with '!'.
+    rules.extend(rewrite!("not-lt"; "(! (< ?x ?y))" <=> "(>= ?x ?y)" if is_int(&["?x", "?y"])));
+    rules.extend(rewrite!("not-gt"; "(! (> ?x ?y))" <=> "(<= ?x ?y)" if is_int(&["?x", "?y"])));
+    rules.extend(rewrite!("not-le"; "(! (<= ?x ?y))" <=> "(> ?x ?y)" if is_int(&["?x", "?y"])));
+    rules.extend(rewrite!("not-ge"; "(! (>= ?x ?y))" <=> "(< ?x ?y)" if is_int(&["?x", "?y"])));
+    rules.extend(rewrite!("not-eq"; "(! (== ?x ?y))" <=> "(!= ?x ?y)"));
+    rules.extend(rewrite!("not-ne"; "(! (!= ?x ?y))" <=> "(== ?x ?y)"));
+    rules.extend(rewrite!("not-zero"; "(! ?x)" <=> "(== ?x 0)"));
+
+    // Boolean connectives. Commutativity of '&&' and '||' is deliberately absent, because
+    // swapping the operands would evaluate the second one when it was previously skipped.
+    rules.extend(vec![
+        rewrite!("and-true"; "(&& 1 ?x)" => "(!= ?x 0)"),
+        rewrite!("and-false"; "(&& 0 ?x)" => "0"),
+        rewrite!("or-true"; "(|| 1 ?x)" => "1"),
+        rewrite!("or-false"; "(|| 0 ?x)" => "(!= ?x 0)"),
+        rewrite!("and-true-right"; "(&& ?x 1)" => "(!= ?x 0)"),
+        rewrite!("or-false-right"; "(|| ?x 0)" => "(!= ?x 0)"),
+        rewrite!("and-self"; "(&& ?x ?x)" => "(!= ?x 0)"),
+        rewrite!("or-self"; "(|| ?x ?x)" => "(!= ?x 0)"),
+    ]);
+    rules.extend(rewrite!("de-morgan-and"; "(! (&& ?x ?y))" <=> "(|| (! ?x) (! ?y))"));
+    rules.extend(rewrite!("de-morgan-or"; "(! (|| ?x ?y))" <=> "(&& (! ?x) (! ?y))"));
+
+    // Conditional expressions. The condition is tested against zero, and is always evaluated,
+    // so it can only be dropped if it cannot fail.
+    rules.extend(vec![
+        rewrite!("select-same"; "(select ?c ?x ?x)" => "?x" if is_safe("?c")),
+        rewrite!("select-true"; "(select 1 ?x ?y)" => "?x"),
+        rewrite!("select-false"; "(select 0 ?x ?y)" => "?y"),
+        rewrite!("select-bool"; "(select ?c 1 0)" => "(!= ?c 0)"),
+        rewrite!("select-not"; "(select (! ?c) ?x ?y)" => "(select ?c ?y ?x)"),
+        rewrite!("select-ne-zero"; "(select (!= ?c 0) ?x ?y)" => "(select ?c ?x ?y)"),
+    ]);
+
+    // Booleans are already 0 or 1, so there is no need to normalize them.
+    rules.extend(vec![
+        rewrite!("ne-zero-bool"; "(!= ?c 0)" => "?c" if is_bool("?c")),
+        rewrite!("eq-one-bool"; "(== ?c 1)" => "?c" if is_bool("?c")),
+        rewrite!("one-minus-bool"; "(- 1 ?c)" => "(! ?c)" if is_bool("?c")),
+    ]);
+
+    // Builtin functions. Which of two equal floats 'min' and 'max' return is unspecified when
+    // they are zeros of different signs, so they are only commutative for ints. 'abs' overflows
+    // on exactly the ints that 'neg' does, so removing a 'neg' from under it changes nothing.
+    // The exponents of 'pow' are literal ints, so these are not float rules, and 'pow(x, 0)' must
+    // keep an 'x' that can fail.
+    rules.extend(vec![
+        rewrite!("min-self"; "(min ?x ?x)" => "?x"),
+        rewrite!("max-self"; "(max ?x ?x)" => "?x"),
+        rewrite!("min-comm"; "(min ?x ?y)" => "(min ?y ?x)" if is_int(&["?x", "?y"])),
+        rewrite!("max-comm"; "(max ?x ?y)" => "(max ?y ?x)" if is_int(&["?x", "?y"])),
+        rewrite!("abs-abs"; "(abs (abs ?x))" => "(abs ?x)"),
+        rewrite!("abs-neg"; "(abs (neg ?x))" => "(abs ?x)"),
+        rewrite!("pow-0"; "(pow ?x 0)" => "1" if is_safe("?x")),
+        rewrite!("pow-1"; "(pow ?x 1)" => "?x"),
+        rewrite!("pow-2"; "(pow ?x 2)" => "(* ?x ?x)"),
+    ]);
+
+    // These hold for ints that wrap, or that never overflow, but not when overflow traps or
+    // saturates: then an intermediate result that overflows changes the outcome. For example,
+    // 'neg (neg x)' traps if x is the smallest int, and 'x + neg y' if y is.
+    if let IntMode::Wrap | IntMode::Big = mode {
+        rules.push(rewrite!("mult-dist-add"; "(* ?a (+ ?b ?c))" => "(+ (* ?a ?b) (* ?a ?c))" if is_int(&["?a", "?b", "?c"])));
+        rules.extend(rewrite!("add-neg"; "(+ ?x (neg ?y))" <=> "(- ?x ?y)"));
+        rules.push(rewrite!("neg-neg"; "(neg (neg ?x))" => "?x"));
+    }
+    rules
+}
+
+// TODO: Split a program into a tree of basic blocks, so that I can optimize each block
+// individually.
+fn optimize(prog: &Program, mode: IntMode) -> Result<Program, Unsupported> {
+    let mut com = EGraphBuilder::new(mode);
+    com.program_to_egraph(prog)?;
 
     let program_root = com.io_root;
-    let runner = Runner::default().with_egraph(com.graph).run(rules);
+    let runner = Runner::<_, _, ()>::new(TypeFacts { mode }).with_egraph(com.graph).run(&rules(mode));
     let extractor = Extractor::new(&runner.egraph, egg::AstSize);
     let (_best_cost, best_expr) = extractor.find_best(program_root);
     // println!("{}", best_expr);
 
+    // Only the main body is optimized; the procedures are dropped, which is fine because
+    // calls are not supported by the egraph translation anyway.
     let new_block = extract_program(&best_expr);
-    let new_prog = Program(new_block);
-
-    new_prog
+    Ok(Program { imports: Vec::new(), records: Vec::new(), consts: Vec::new(), procs: Vec::new(), body: new_block })
 }
 
-fn execute(prog: &Program, args: Vec<i64>) {
-    let mut com = Compiler::new();
-    com.compile_program(&prog);
+// Returns false if the program does not compile.
+fn execute(prog: &Program, args: Vec<Value>, input: &str, mode: IntMode) -> bool {
+    let mut com = Compiler::new(mode);
+    if let Err(err) = com.compile_program(prog) {
+        println!("error: {}", err);
+        return false;
+    }
 
     let code = com.output();
     println!("--- Compiled bytecode: ---");
-    println!("{:?}", code);
+    println!("{:?}", code.insns);
+    if !code.strings.is_empty() {
+        println!("strings = {:?}", code.strings);
+    }
 
-    let mut vm = VM::new(code, args);
+    let mut vm = VM::new(code, args, mode);
+    vm.set_input(Box::new(std::io::Cursor::new(input.to_string())));
 
     println!("--- Results: ---");
-    vm.execute();
-    vm.dump_state();
+    match vm.execute() {
+        Ok(_status) => vm.dump_state(),
+        // Errors are part of the results: the optimized program should fail in the same way.
+        Err(err) => println!("runtime error: {}", err),
+    }
+    true
 }
 
-fn run_program(src: &str, args: Vec<i64>) {
+// Both versions of the program are given the same arguments and input, so that their output
+// should be the same.
+fn run_program(src: &str, args: Vec<Value>, input: &str, mode: IntMode) {
     let parser = ProgramParser::new();
 
     println!("Original program:");
-    let prog = parser.parse(src).expect("valid syntax");
-    execute(&prog, args.clone());
+    let prog = parser.parse(0, src).expect("valid syntax");
+    typecheck::check_program(&prog).expect("program should be well-typed");
+    if !execute(&prog, args.clone(), input, mode) {
+        return;
+    }
 
-    println!("");
-    println!("Optimized program:");
-    let new_prog = optimize(&prog);
-    execute(&new_prog, args.clone());
+    println!();
+    match optimize(&prog, mode) {
+        Ok(new_prog) => {
+            println!("Optimized program:");
+            execute(&new_prog, args.clone(), input, mode);
+        },
+        Err(err) => println!("Not optimized: {}", err),
+    }
 }
 
-pub fn demo() {
+// Run the program in 'src_filename' before and after optimizing it.
+pub fn demo(src_filename: &str, mode: IntMode) {
     // println!("EGG");
 
-    let src_filename = "demo.prog";
     let src = std::fs::read_to_string(src_filename).expect("src file does not exist");
-    run_program(&src, vec![3]);
+    run_program(&src, vec![Value::Int(3)], "1 2 3\n", mode);
 
     // let mut expr = RecExpr::default();
     // let a1 = expr.add(SymbolLang::leaf("a"));
@@ -394,19 +1067,27 @@ pub fn demo() {
 }
 
 
+#[allow(dead_code)]
 enum BBStmt {
+    Let(Var, Box<Expr>),
     Assign(Var, Box<Expr>),
-    Print(Box<Expr>),
+    Print(Vec<PrintItem>, bool),
+    Assert(Box<Expr>, Span),
+    Read(Var),
 }
 
+#[allow(dead_code)]
 struct BB(Vec<BBStmt>);
 
+#[allow(dead_code)]
 enum BBTree {
     Leaf(BB),
     If(IfTree),
     While(WhileTree),
+    For(ForTree),
 }
 
+#[allow(dead_code)]
 struct IfTree {
     pre: Box<BBTree>,
     cond: Box<Expr>,
@@ -415,6 +1096,7 @@ struct IfTree {
     post: Box<BBTree>,
 }
 
+#[allow(dead_code)]
 struct WhileTree {
     pre: Box<BBTree>,
     cond: Box<Expr>,
@@ -422,6 +1104,19 @@ struct WhileTree {
     post: Box<BBTree>,
 }
 
+// A counted loop keeps its induction variable explicit, rather than being lowered to a WhileTree.
+#[allow(dead_code)]
+struct ForTree {
+    pre: Box<BBTree>,
+    var: Var,
+    start: Box<Expr>,
+    bound: Box<Expr>,
+    step: Option<Box<Expr>>,
+    body: Box<BBTree>,
+    post: Box<BBTree>,
+}
+
+#[allow(dead_code)]
 impl BB {
     fn new() -> Self {
         BB(vec![])
@@ -432,41 +1127,56 @@ impl BB {
     }
 }
 
+#[allow(dead_code)]
 impl IfTree {
     fn push_stmt(&mut self, stmt: BBStmt) {
         self.post.push_stmt(stmt);
     }
 }
 
+#[allow(dead_code)]
 impl WhileTree {
     fn push_stmt(&mut self, stmt: BBStmt) {
         self.post.push_stmt(stmt);
     }
 }
 
+#[allow(dead_code)]
+impl ForTree {
+    fn push_stmt(&mut self, stmt: BBStmt) {
+        self.post.push_stmt(stmt);
+    }
+}
+
+#[allow(dead_code)]
 impl BBTree {
     fn push_stmt(&mut self, stmt: BBStmt) {
         match self {
             BBTree::Leaf(ref mut bb) => bb.push_stmt(stmt),
             BBTree::If(ref mut node) => node.push_stmt(stmt),
             BBTree::While(ref mut node) => node.push_stmt(stmt),
+            BBTree::For(ref mut node) => node.push_stmt(stmt),
         }
     }
 }
 
-fn program_build_bb_tree(prog: &Program) -> BBTree {
-    block_build_bb_tree(&prog.0)
+#[allow(dead_code)]
+fn program_build_bb_tree(prog: &Program) -> Result<BBTree, Unsupported> {
+    block_build_bb_tree(&prog.body)
 }
 
-fn block_build_bb_tree(block: &Block) -> BBTree {
+#[allow(dead_code)]
+fn block_build_bb_tree(block: &Block) -> Result<BBTree, Unsupported> {
     let mut tree = BBTree::Leaf(BB::new());
-    for s in &block.0 {
+    for (_span, s) in &block.0 {
         match s {
+            Stmt::Let(x, e) => tree.push_stmt(BBStmt::Let(x.clone(), (*e).clone())),
             Stmt::Assign(x, e) => tree.push_stmt(BBStmt::Assign(x.clone(), (*e).clone())),
-            Stmt::Print(e) => tree.push_stmt(BBStmt::Print((*e).clone())),
+            Stmt::Print(items, newline) => tree.push_stmt(BBStmt::Print(items.clone(), *newline)),
+            Stmt::Read(x) => tree.push_stmt(BBStmt::Read(x.clone())),
             Stmt::If(e, bt, bf) => {
-                let ttree = block_build_bb_tree(bt);
-                let ftree = block_build_bb_tree(bf);
+                let ttree = block_build_bb_tree(bt)?;
+                let ftree = block_build_bb_tree(bf)?;
                 tree = BBTree::If(IfTree {
                     pre: Box::new(tree),
                     cond: (*e).clone(),
@@ -476,7 +1186,7 @@ fn block_build_bb_tree(block: &Block) -> BBTree {
                 });
             },
             Stmt::While(e, b) => {
-                let btree = block_build_bb_tree(b);
+                let btree = block_build_bb_tree(b)?;
                 tree = BBTree::While(WhileTree {
                     pre: Box::new(tree),
                     cond: (*e).clone(),
@@ -484,10 +1194,29 @@ fn block_build_bb_tree(block: &Block) -> BBTree {
                     post: Box::new(BBTree::Leaf(BB::new())),
                 });
             },
+            Stmt::For(x, lo, hi, step, b) => {
+                let btree = block_build_bb_tree(b)?;
+                tree = BBTree::For(ForTree {
+                    pre: Box::new(tree),
+                    var: x.clone(),
+                    start: (*lo).clone(),
+                    bound: (*hi).clone(),
+                    step: step.clone(),
+                    body: Box::new(btree),
+                    post: Box::new(BBTree::Leaf(BB::new())),
+                });
+            },
+            Stmt::Call(..) | Stmt::Return(..) => return Err(Unsupported("procedure calls")),
+            Stmt::Break | Stmt::Continue => return Err(Unsupported("loop exits")),
+            Stmt::Exit(..) => return Err(Unsupported("exit statements")),
+            Stmt::Match(..) => return Err(Unsupported("match statements")),
+            Stmt::Assert(e, span) => tree.push_stmt(BBStmt::Assert((*e).clone(), *span)),
+            Stmt::Store(..) => return Err(Unsupported("arrays")),
+            Stmt::SetField(..) => return Err(Unsupported("records")),
         }
     }
 
-    tree
+    Ok(tree)
 }
 
 // TODO: Convert BBTree to egraph. whole-program egraph or per-block egraph?
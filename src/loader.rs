@@ -0,0 +1,166 @@
+// Loading a program from its source files. A file may import others, whose records, constants and
+// procedures are linked into a single program, in the order that the files are first imported,
+// so that each file's declarations come after those of the files that it imports. Importing the
+// same file twice only links it once.
+//
+// Spans are offsets into the concatenation of every file, so a span identifies the file that it
+// is in as well as the text.
+
+use crate::parser::ProgramParser;
+use crate::syntax::{Block, Program, Span};
+use lalrpop_util::ParseError;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+struct SourceFile {
+    // The path as it was given, relative to the current directory.
+    path: PathBuf,
+    // The path without any links or '..', so that different ways of referring to the same file
+    // can be recognized.
+    canonical: PathBuf,
+    text: String,
+    // The offset of the start of the text, for spans.
+    base: usize,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    // The span is that of the import, unless the file is the main one.
+    Unreadable { path: PathBuf, span: Option<Span>, err: std::io::Error },
+    Syntax { span: Span, message: String },
+    // Each file imports the next, and the last one is the first.
+    Cycle { span: Span, files: Vec<PathBuf> },
+    // The span is that of the first statement outside of a procedure in an imported file.
+    StatementsInImport(Span),
+}
+
+impl LoadError {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            LoadError::Unreadable { span, .. } => *span,
+            LoadError::Syntax { span, .. } | LoadError::Cycle { span, .. } | LoadError::StatementsInImport(span) => {
+                Some(*span)
+            },
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Unreadable { path, err, .. } => write!(f, "cannot read '{}': {}", path.display(), err),
+            LoadError::Syntax { message, .. } => write!(f, "syntax error: {}", message),
+            LoadError::Cycle { files, .. } => {
+                let files: Vec<String> = files.iter().map(|p| p.display().to_string()).collect();
+                write!(f, "import cycle: {}", files.join(" -> "))
+            },
+            LoadError::StatementsInImport(_) => write!(f, "an imported file may only contain declarations"),
+        }
+    }
+}
+
+pub struct Sources {
+    files: Vec<SourceFile>,
+}
+
+impl Sources {
+    pub fn new() -> Self {
+        Sources { files: Vec::new() }
+    }
+
+    fn file(&self, span: Span) -> &SourceFile {
+        self.files.iter().rev().find(|file| file.base <= span.start).unwrap()
+    }
+
+    fn relative(file: &SourceFile, span: Span) -> Span {
+        Span { start: span.start - file.base, end: span.end - file.base }
+    }
+
+    // Where a span is, for error messages. Only spans in imported files name the file.
+    pub fn describe(&self, span: Span) -> String {
+        let file = self.file(span);
+        let line = Self::relative(file, span).line(&file.text);
+        if std::ptr::eq(file, &self.files[0]) {
+            format!("line {}", line)
+        } else {
+            format!("line {} of {}", line, file.path.display())
+        }
+    }
+
+    pub fn text(&self, span: Span) -> &str {
+        let file = self.file(span);
+        Self::relative(file, span).text(&file.text)
+    }
+
+    // Load the program whose main file is at 'path'.
+    pub fn load(&mut self, path: &Path) -> Result<Program, LoadError> {
+        let mut program = Program {
+            imports: Vec::new(),
+            records: Vec::new(),
+            consts: Vec::new(),
+            procs: Vec::new(),
+            body: Block(Vec::new()),
+        };
+        program.body = self.load_file(path, None, &mut Vec::new(), &mut program)?;
+        Ok(program)
+    }
+
+    // Add the declarations of a file, and of everything that it imports, to 'program', unless
+    // they have been already. Returns the statements outside of any procedure. 'loading' holds
+    // the files whose imports are being loaded, so that a cycle is noticed.
+    fn load_file(&mut self, path: &Path, import: Option<Span>, loading: &mut Vec<PathBuf>,
+                 program: &mut Program) -> Result<Block, LoadError> {
+        let unreadable = |err| LoadError::Unreadable { path: path.to_path_buf(), span: import, err };
+        let canonical = std::fs::canonicalize(path).map_err(unreadable)?;
+        if let Some(i) = loading.iter().position(|p| *p == canonical) {
+            let mut files: Vec<PathBuf> = self.files.iter()
+                .filter(|file| loading[i..].contains(&file.canonical))
+                .map(|file| file.path.clone())
+                .collect();
+            files.push(path.to_path_buf());
+            return Err(LoadError::Cycle { span: import.unwrap(), files });
+        }
+        if self.files.iter().any(|file| file.canonical == canonical) {
+            return Ok(Block(Vec::new()));
+        }
+
+        let text = std::fs::read_to_string(path).map_err(unreadable)?;
+        // Leave a gap between files, so that an empty file still has a span of its own.
+        let base = self.files.last().map_or(0, |file| file.base + file.text.len() + 1);
+        self.files.push(SourceFile { path: path.to_path_buf(), canonical: canonical.clone(), text, base });
+        let text = &self.files.last().unwrap().text;
+        let p = ProgramParser::new().parse(base, text).map_err(|err| syntax_error(err, base))?;
+
+        loading.push(canonical);
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for import in &p.imports {
+            let body = self.load_file(&dir.join(&import.path), Some(import.span), loading, program)?;
+            if let Some((span, _)) = body.0.first() {
+                return Err(LoadError::StatementsInImport(*span));
+            }
+        }
+        loading.pop();
+
+        program.records.extend(p.records);
+        program.consts.extend(p.consts);
+        program.procs.extend(p.procs);
+        Ok(p.body)
+    }
+}
+
+fn syntax_error<T: fmt::Display>(err: ParseError<usize, T, (usize, &str)>, base: usize) -> LoadError {
+    let at = |offset: usize| Span { start: offset + base, end: offset + base };
+    let (span, message) = match err {
+        ParseError::InvalidToken { location } => (at(location), "invalid token".to_string()),
+        ParseError::UnrecognizedEof { location, .. } => (at(location), "unexpected end of file".to_string()),
+        // The magnitude of the smallest int is a token of its own, which is only a literal when it
+        // is negated. Anywhere else, it is just too large.
+        ParseError::UnrecognizedToken { token: (start, token, _), .. } |
+        ParseError::ExtraToken { token: (start, token, _) } if token.to_string() == "9223372036854775808" =>
+            (at(start), "integer literal is too large".to_string()),
+        ParseError::UnrecognizedToken { token: (start, token, _), .. } |
+        ParseError::ExtraToken { token: (start, token, _) } => (at(start), format!("unexpected '{}'", token)),
+        ParseError::User { error: (location, message) } => (at(location), message.to_string()),
+    };
+    LoadError::Syntax { span, message }
+}
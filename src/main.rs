@@ -1,32 +1,85 @@
 
 use lalrpop_util::lalrpop_mod;
 
-lalrpop_mod!(parser);
+lalrpop_mod!(#[allow(clippy::all)] parser);
 mod compiler;
 mod syntax;
+mod typecheck;
 mod egraph;
+mod loader;
 
-use parser::ProgramParser;
+use compiler::{Compiler, VM, RuntimeError, Value, IntMode};
+use loader::Sources;
+use num_bigint::BigInt;
+use std::path::Path;
 
-use compiler::{Compiler, VM};
 
+// Returns the exit status of the program.
+fn run_program(path: &Path, args: Vec<Value>, mode: IntMode) -> i64 {
+    let mut sources = Sources::new();
+    let p = match sources.load(path) {
+        Ok(p) => p,
+        Err(err) => {
+            match err.span() {
+                Some(span) => eprintln!("{}: {}", sources.describe(span), err),
+                None => eprintln!("error: {}", err),
+            }
+            std::process::exit(1);
+        },
+    };
 
-fn run_program(src: &str, args: Vec<i64>) {
-    let parser = ProgramParser::new();
-    let p = parser.parse(src).expect("valid syntax");
+    if let Err(err) = typecheck::check_program(&p) {
+        eprintln!("{}: type error: {}", sources.describe(err.span), err);
+        std::process::exit(1);
+    }
 
-    let mut com = Compiler::new();
-    com.compile_program(&p);
+    let mut com = Compiler::new(mode);
+    if let Err(err) = com.compile_program(&p) {
+        eprintln!("{}: error: {}", sources.describe(com.location()), err);
+        std::process::exit(1);
+    }
 
     let code = com.output();
     println!("--- Compiled bytecode: ---");
-    println!("{:?}", code);
+    println!("{:?}", code.insns);
+    if !code.strings.is_empty() {
+        println!("strings = {:?}", code.strings);
+    }
+    if !code.tables.is_empty() {
+        println!("tables = {:?}", code.tables);
+    }
 
-    let mut vm = VM::new(code, args);
+    let mut vm = VM::new(code, args, mode);
 
     println!("--- Results: ---");
-    vm.execute();
+    let status = match vm.execute() {
+        Ok(status) => status,
+        Err(RuntimeError::AssertionFailed(span)) => {
+            eprintln!("{}: assertion failed: {}", sources.describe(span), sources.text(span));
+            std::process::exit(1);
+        },
+        Err(err) => {
+            eprintln!("{}: runtime error: {}", sources.describe(vm.location()), err);
+            std::process::exit(1);
+        },
+    };
     vm.dump_state();
+    status
+}
+
+// An argument with a decimal point or exponent is a float, and any other is an int. With
+// '--bignum', ints may be of any size.
+fn parse_arg(arg: &str, mode: IntMode) -> Result<Value, String> {
+    let not_a_number = || format!("argument '{}' is not a number", arg);
+    if arg.contains(['.', 'e', 'E']) {
+        return arg.parse::<f64>().map(Value::Float).map_err(|_| not_a_number());
+    }
+    let big = arg.parse::<BigInt>().map_err(|_| not_a_number())?;
+    match i64::try_from(&big) {
+        Ok(i) => Ok(Value::Int(i)),
+        Err(_) if mode == IntMode::Big => Ok(Value::from_big(big)),
+        Err(_) => Err(format!("argument '{}' does not fit in 64 bits; use --bignum for larger ints", arg)),
+    }
 }
 
 fn main() {
@@ -34,16 +87,43 @@ fn main() {
     // let src = "x = 3; y = x * 2; x = x + 1; y = x + y; print y;";
     // let src = "x = 5; y = 3; if x > y then print 2; else print 4; end";
 
-    let mut args = std::env::args();
+    let mut args = std::env::args().peekable();
     args.next().unwrap(); // skip argv[0]
-    let src_filename = args.next().expect("a filename on the command line");
+
+    // Options come before the filename.
+    let mut mode = IntMode::Wrap;
+    while let Some(option) = args.next_if(|a| a.starts_with("--")) {
+        match option.as_str() {
+            "--overflow=wrap" => mode = IntMode::Wrap,
+            "--overflow=trap" => mode = IntMode::Trap,
+            "--overflow=saturate" => mode = IntMode::Saturate,
+            "--bignum" => mode = IntMode::Big,
+            _ => {
+                eprintln!("unknown option '{}'", option);
+                std::process::exit(1);
+            },
+        }
+    }
+
+    let Some(src_filename) = args.next() else {
+        eprintln!("usage: project [options] file [args...]");
+        std::process::exit(1);
+    };
+    // 'TEST' optimizes a program, 'demo.prog' unless another is given, and runs it before and
+    // after.
     if src_filename == "TEST" {
-        egraph::demo();
+        egraph::demo(&args.next().unwrap_or("demo.prog".to_string()), mode);
         return;
     }
-    let src = std::fs::read_to_string(src_filename).expect("file should exist");
-
-    let arg_vals = args.map(|n| n.parse::<i64>().unwrap()).collect();
+    let arg_vals = match args.map(|arg| parse_arg(&arg, mode)).collect() {
+        Ok(vals) => vals,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        },
+    };
 
-    run_program(&src, arg_vals);
+    let status = run_program(Path::new(&src_filename), arg_vals, mode);
+    // The VM only halts with a status in 0..=255, which every OS can report.
+    std::process::exit(status as i32);
 }
@@ -1,13 +1,128 @@
+use std::fmt;
 
-#[derive(Clone, PartialEq, Eq, Hash)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Var(pub String);
 
+impl fmt::Display for Var {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// A region of the source text, as byte offsets.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    // The (1-based) line of the source on which this span starts.
+    pub fn line(&self, src: &str) -> usize {
+        src[..self.start].matches('\n').count() + 1
+    }
+
+    pub fn text<'a>(&self, src: &'a str) -> &'a str {
+        &src[self.start..self.end]
+    }
+}
+
+// Spans are written as '@start..end' in egraph patterns and dumps.
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "@{}..{}", self.start, self.end)
+    }
+}
+
+impl std::str::FromStr for Span {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let (start, end) = s.strip_prefix('@').and_then(|s| s.split_once("..")).ok_or(())?;
+        let start = start.parse().map_err(|_| ())?;
+        let end = end.parse().map_err(|_| ())?;
+        Ok(Span { start, end })
+    }
+}
+
 #[derive(Clone)]
 pub enum Expr {
     Var(Var),
     Num(i64),
+    Float(f64),
+    Bool(bool),
     BinOp(BinOp, Box<Expr>, Box<Expr>),
+    Neg(Box<Expr>),
     Input(Box<Expr>),
+    Call(Var, Vec<Expr>),
+    // A call to a builtin function, such as 'abs(x)'.
+    Builtin(Builtin, Vec<Expr>),
+    // 'array(n)' is filled with zeros, and 'array(n, v)' with copies of v.
+    NewArray(Box<Expr>, Option<Box<Expr>>),
+    Index(Box<Expr>, Box<Expr>),
+    Len(Box<Expr>),
+    // 'Point { x: 1, y: 2 }'. Every field must be given a value, in any order.
+    Record(Var, Vec<(Var, Expr)>),
+    // 'p.x'
+    Field(Box<Expr>, Var),
+    // Conversions between ints and floats: 'float(e)' and 'int(e)'. The latter truncates towards
+    // zero.
+    ToFloat(Box<Expr>),
+    ToInt(Box<Expr>),
+    // Short-circuiting boolean connectives. These evaluate to 0 or 1.
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    // 'if c then e1 else e2'. Only the chosen branch is evaluated.
+    If(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+// The builtin functions. Their names are not keywords, but a procedure cannot be given one of
+// them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Builtin {
+    // The number of command-line arguments, i.e. how many can be read by 'input'.
+    Argc,
+    // Whether a 'read' has reached the end of the input.
+    Eof,
+    Abs,
+    Min,
+    Max,
+    // 'pow(x, n)'. For ints, n must not be negative.
+    Pow,
+}
+
+impl Builtin {
+    pub fn from_name(name: &str) -> Option<Builtin> {
+        match name {
+            "argc" => Some(Builtin::Argc),
+            "eof" => Some(Builtin::Eof),
+            "abs" => Some(Builtin::Abs),
+            "min" => Some(Builtin::Min),
+            "max" => Some(Builtin::Max),
+            "pow" => Some(Builtin::Pow),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Builtin::Argc => "argc",
+            Builtin::Eof => "eof",
+            Builtin::Abs => "abs",
+            Builtin::Min => "min",
+            Builtin::Max => "max",
+            Builtin::Pow => "pow",
+        }
+    }
+
+    pub fn arity(&self) -> usize {
+        match self {
+            Builtin::Argc | Builtin::Eof => 0,
+            Builtin::Abs => 1,
+            Builtin::Min | Builtin::Max | Builtin::Pow => 2,
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -15,18 +130,109 @@ pub enum BinOp {
     Add,
     Sub,
     Mul,
+    Div,
+    Mod,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
     Lt,
     Gt,
+    Eq,
+    Ne,
+    Le,
+    Ge,
 }
 
 pub enum Stmt {
+    // 'let x = e;' declares a new variable, scoped to the end of the enclosing block.
+    Let(Var, Box<Expr>),
     Assign(Var, Box<Expr>),
+    // 'a[i] = e;'
+    Store(Var, Box<Expr>, Box<Expr>),
+    // 'p.x = e;'
+    SetField(Var, Var, Box<Expr>),
+    // 'read x;' assigns the next int from the input to x. At the end of the input, it assigns 0,
+    // and 'eof()' becomes true.
+    Read(Var),
     If(Box<Expr>, Block, Block),
     While(Box<Expr>, Block),
-    Print(Box<Expr>),
+    // 'for i in lo..hi step s do body end'. The step is optional, and defaults to 1.
+    For(Var, Box<Expr>, Box<Expr>, Option<Box<Expr>>, Block),
+    // Print each item in turn, followed by a newline if the flag is set. The parser puts a space
+    // between adjacent values, so that they are never printed as one number.
+    Print(Vec<PrintItem>, bool),
+    Call(Var, Vec<Expr>),
+    Return(Box<Expr>),
+    Break,
+    Continue,
+    // 'match e with 0 => ... 1 => ... _ => ... end'. The body of the first case whose value is
+    // equal to e is run, or else that of the default case, '_', which is represented by 'None'.
+    Match(Box<Expr>, Vec<(Option<i64>, Block)>),
+    // Stop the program, with the given exit status.
+    Exit(Box<Expr>),
+    // Halt with an error if the expression is zero. The span covers the asserted expression.
+    Assert(Box<Expr>, Span),
+}
+
+#[derive(Clone)]
+pub enum PrintItem {
+    Str(String),
+    Expr(Box<Expr>),
+}
+
+impl PrintItem {
+    // Put a space between each pair of adjacent values, as in 'print x, y;'. Strings are printed
+    // as they are, so 'print "x=", x;' gets no extra space.
+    pub fn separate(items: Vec<PrintItem>) -> Vec<PrintItem> {
+        let mut separated = Vec::with_capacity(items.len());
+        for item in items {
+            if let (Some(PrintItem::Expr(_)), PrintItem::Expr(_)) = (separated.last(), &item) {
+                separated.push(PrintItem::Str(" ".to_string()));
+            }
+            separated.push(item);
+        }
+        separated
+    }
 }
 
-pub struct Block(pub Vec<Stmt>);
+// Each statement is paired with the span of source text that it was parsed from.
+pub struct Block(pub Vec<(Span, Stmt)>);
 
-pub struct Program(pub Block);
+// A procedure declaration: 'def name(params) body end'. The span covers the first line.
+pub struct Proc {
+    pub name: Var,
+    pub params: Vec<Var>,
+    pub body: Block,
+    pub span: Span,
+}
 
+// A named constant: 'const N = e;'. The initializer is evaluated at compile time.
+pub struct Const {
+    pub name: Var,
+    pub value: Expr,
+    pub span: Span,
+}
+
+// A record type: 'record Point { x, y }'. Records live on the heap, like arrays, and their fields
+// can be updated.
+pub struct Record {
+    pub name: Var,
+    pub fields: Vec<Var>,
+    pub span: Span,
+}
+
+// 'import "lib.prog";'. The path is relative to the directory of the importing file.
+pub struct Import {
+    pub path: String,
+    pub span: Span,
+}
+
+pub struct Program {
+    pub imports: Vec<Import>,
+    pub records: Vec<Record>,
+    pub consts: Vec<Const>,
+    pub procs: Vec<Proc>,
+    pub body: Block,
+}
@@ -0,0 +1,532 @@
+// Static type checking. Booleans are still the integers 0 and 1 at runtime, so this pass only
+// rejects programs: it has no effect on the code that is generated for them.
+//
+// The types of parameters and return values are inferred, by unifying them with the types of
+// arguments and returned expressions. Procedures are monomorphic, so every call to a procedure
+// must agree on the types of its arguments.
+//
+// Arithmetic and comparisons work on both ints and floats, as long as both operands have the same
+// type, and inputs may be either. When the type of an operand is not known yet, the requirement
+// that it be a number is checked once the whole program has been.
+//
+// Record types are distinguished by name. The types of their fields are inferred, like those of
+// parameters. Several records may have a field with the same name, so accessing a field of a
+// value whose type is not known yet is checked once it is.
+//
+// Names that are not declared are given an unknown type, and left for the compiler to report.
+
+use crate::syntax::{Expr, BinOp, Builtin, Var, Stmt, PrintItem, Block, Program, Span};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Type {
+    Int,
+    Float,
+    Bool,
+    Array(Box<Type>),
+    Record(Var),
+    // A type that has not been determined yet, such as that of a parameter. The index refers to
+    // the checker's table of solutions.
+    Unknown(usize),
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Float => write!(f, "float"),
+            Type::Bool => write!(f, "bool"),
+            Type::Array(t) => match **t {
+                Type::Unknown(_) => write!(f, "array"),
+                ref t => write!(f, "array of {}", t),
+            },
+            Type::Record(r) => write!(f, "{}", r),
+            Type::Unknown(_) => write!(f, "_"),
+        }
+    }
+}
+
+// The span is that of the statement containing the ill-typed expression. Any of the expected
+// types would have been accepted.
+#[derive(Debug)]
+pub struct TypeError {
+    pub span: Span,
+    pub expected: Vec<Type>,
+    pub found: Type,
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let expected: Vec<String> = self.expected.iter().map(|t| t.to_string()).collect();
+        write!(f, "expected a value of type {}, but found {}", expected.join(" or "), self.found)
+    }
+}
+
+struct Checker {
+    solutions: Vec<Option<Type>>,
+    // The variables in scope, one map per enclosing block, innermost last.
+    scopes: Vec<HashMap<Var, Type>>,
+    consts: HashMap<Var, Type>,
+    // The fields of each record type, in the order the records are declared.
+    records: Vec<(Var, Vec<(Var, Type)>)>,
+    // The parameter and return types of each procedure.
+    procs: HashMap<Var, (Vec<Type>, Type)>,
+    // The return type of the procedure being checked, if any.
+    ret: Option<Type>,
+    // Types that must turn out to be one of a few alternatives, once they are known.
+    constraints: Vec<(Span, Type, Vec<Type>)>,
+    // Field accesses whose record type was not known yet, with the type of the field.
+    fields: Vec<(Span, Type, Var, Type)>,
+    // The statement being checked.
+    span: Span,
+}
+
+impl Checker {
+    fn new() -> Self {
+        Checker {
+            solutions: Vec::new(),
+            scopes: Vec::new(),
+            consts: HashMap::new(),
+            records: Vec::new(),
+            procs: HashMap::new(),
+            ret: None,
+            constraints: Vec::new(),
+            fields: Vec::new(),
+            span: Span { start: 0, end: 0 },
+        }
+    }
+
+    fn fresh(&mut self) -> Type {
+        self.solutions.push(None);
+        Type::Unknown(self.solutions.len() - 1)
+    }
+
+    // Follow solutions until reaching a type that is either known or not yet solved.
+    fn shallow(&self, t: &Type) -> Type {
+        match t {
+            Type::Unknown(i) => match &self.solutions[*i] {
+                Some(t) => self.shallow(t),
+                None => t.clone(),
+            },
+            t => t.clone(),
+        }
+    }
+
+    // Substitute every solved unknown in a type.
+    fn resolve(&self, t: &Type) -> Type {
+        match self.shallow(t) {
+            Type::Array(t) => Type::Array(Box::new(self.resolve(&t))),
+            t => t,
+        }
+    }
+
+    fn occurs(&self, i: usize, t: &Type) -> bool {
+        match self.shallow(t) {
+            Type::Unknown(j) => i == j,
+            Type::Array(t) => self.occurs(i, &t),
+            _ => false,
+        }
+    }
+
+    fn unify(&mut self, t1: &Type, t2: &Type) -> bool {
+        match (self.shallow(t1), self.shallow(t2)) {
+            (Type::Unknown(i), Type::Unknown(j)) if i == j => true,
+            (Type::Unknown(i), t) | (t, Type::Unknown(i)) => {
+                // An array cannot contain itself.
+                if self.occurs(i, &t) {
+                    return false;
+                }
+                self.solutions[i] = Some(t);
+                true
+            },
+            (Type::Array(t1), Type::Array(t2)) => self.unify(&t1, &t2),
+            (t1, t2) => t1 == t2,
+        }
+    }
+
+    fn expect(&mut self, expected: &Type, found: &Type) -> Result<(), TypeError> {
+        if self.unify(expected, found) {
+            Ok(())
+        } else {
+            Err(TypeError { span: self.span, expected: vec![self.resolve(expected)], found: self.resolve(found) })
+        }
+    }
+
+    // Require a type to be one of the alternatives, now if it is known, or later otherwise.
+    fn constrain(&mut self, t: &Type, alternatives: &[Type]) -> Result<(), TypeError> {
+        self.constraints.push((self.span, t.clone(), alternatives.to_vec()));
+        self.check_constraint(self.constraints.len() - 1)
+    }
+
+    fn check_constraint(&self, i: usize) -> Result<(), TypeError> {
+        let (span, t, alternatives) = &self.constraints[i];
+        match self.resolve(t) {
+            Type::Unknown(_) => Ok(()),
+            t if alternatives.contains(&t) => Ok(()),
+            t => Err(TypeError { span: *span, expected: alternatives.clone(), found: t }),
+        }
+    }
+
+    // Check an arithmetic operator or comparison, returning the type of its operands.
+    fn check_numeric(&mut self, e1: &Expr, e2: &Expr) -> Result<Type, TypeError> {
+        let t = self.check_exp(e1)?;
+        self.expect_exp(&t, e2)?;
+        self.constrain(&t, &[Type::Int, Type::Float])?;
+        Ok(t)
+    }
+
+    fn field_type(&self, record: &Var, x: &Var) -> Option<Type> {
+        let (_, fields) = self.records.iter().find(|(r, _)| r == record)?;
+        fields.iter().find(|(y, _)| y == x).map(|(_, t)| t.clone())
+    }
+
+    // The record types that have a field named 'x'.
+    fn records_with(&self, x: &Var) -> Vec<Type> {
+        self.records.iter()
+            .filter(|(_, fields)| fields.iter().any(|(y, _)| y == x))
+            .map(|(r, _)| Type::Record(r.clone()))
+            .collect()
+    }
+
+    // The type of field 'x' of a value of type 't'. If 't' is not known yet, but only one record
+    // has such a field, then it must be that record.
+    fn check_field(&mut self, t: &Type, x: &Var) -> Result<Type, TypeError> {
+        let candidates = self.records_with(x);
+        match self.shallow(t) {
+            // No record has such a field, which is left for the compiler to report.
+            _ if candidates.is_empty() => Ok(self.fresh()),
+            Type::Record(r) if candidates.contains(&Type::Record(r.clone())) => Ok(self.field_type(&r, x).unwrap()),
+            Type::Unknown(_) if candidates.len() == 1 => {
+                self.expect(&candidates[0], t)?;
+                self.check_field(t, x)
+            },
+            Type::Unknown(_) => {
+                let field = self.fresh();
+                self.fields.push((self.span, t.clone(), x.clone(), field.clone()));
+                Ok(field)
+            },
+            t => Err(TypeError { span: self.span, expected: candidates, found: self.resolve(&t) }),
+        }
+    }
+
+    // Check the field accesses that were put off, for as long as that makes the types of more
+    // records known.
+    fn check_pending_fields(&mut self) -> Result<(), TypeError> {
+        loop {
+            let pending = std::mem::take(&mut self.fields);
+            let before = pending.len();
+            for (span, t, x, field) in pending {
+                if let Type::Unknown(_) = self.shallow(&t) {
+                    self.fields.push((span, t, x, field));
+                    continue;
+                }
+                self.span = span;
+                let found = self.check_field(&t, &x)?;
+                self.expect(&field, &found)?;
+            }
+            if self.fields.len() == before {
+                break;
+            }
+        }
+        // Any that remain are ambiguous.
+        match self.fields.first() {
+            None => Ok(()),
+            Some((span, t, x, _)) => Err(TypeError { span: *span, expected: self.records_with(x), found: self.resolve(t) }),
+        }
+    }
+
+    fn expect_exp(&mut self, expected: &Type, e: &Expr) -> Result<(), TypeError> {
+        let found = self.check_exp(e)?;
+        self.expect(expected, &found)
+    }
+
+    fn lookup(&mut self, x: &Var) -> Type {
+        let t = self.scopes.iter().rev().find_map(|scope| scope.get(x)).or_else(|| self.consts.get(x));
+        match t {
+            Some(t) => t.clone(),
+            None => self.fresh(),
+        }
+    }
+
+    fn declare(&mut self, x: &Var, t: Type) {
+        self.scopes.last_mut().unwrap().insert(x.clone(), t);
+    }
+
+    fn check_exp(&mut self, e: &Expr) -> Result<Type, TypeError> {
+        let t = match e {
+            Expr::Var(x) => self.lookup(x),
+            Expr::Num(_) => Type::Int,
+            Expr::Float(_) => Type::Float,
+            Expr::Bool(_) => Type::Bool,
+            Expr::BinOp(op, e1, e2) => match op {
+                BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Mod => self.check_numeric(e1, e2)?,
+                BinOp::BitAnd | BinOp::BitOr | BinOp::BitXor | BinOp::Shl | BinOp::Shr => {
+                    self.expect_exp(&Type::Int, e1)?;
+                    self.expect_exp(&Type::Int, e2)?;
+                    Type::Int
+                },
+                BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge => {
+                    self.check_numeric(e1, e2)?;
+                    Type::Bool
+                },
+                // Values of any type can be compared for equality, as long as both sides agree.
+                BinOp::Eq | BinOp::Ne => {
+                    let t1 = self.check_exp(e1)?;
+                    self.expect_exp(&t1, e2)?;
+                    Type::Bool
+                },
+            },
+            Expr::Neg(e) => {
+                let t = self.check_exp(e)?;
+                self.constrain(&t, &[Type::Int, Type::Float])?;
+                t
+            },
+            Expr::Input(e) => {
+                self.expect_exp(&Type::Int, e)?;
+                let t = self.fresh();
+                self.constrain(&t, &[Type::Int, Type::Float])?;
+                t
+            },
+            Expr::ToFloat(e) => {
+                self.expect_exp(&Type::Int, e)?;
+                Type::Float
+            },
+            Expr::ToInt(e) => {
+                self.expect_exp(&Type::Float, e)?;
+                Type::Int
+            },
+            Expr::Call(f, args) => self.check_call(f, args)?,
+            Expr::Builtin(b, args) => self.check_builtin(*b, args)?,
+            Expr::NewArray(n, fill) => {
+                self.expect_exp(&Type::Int, n)?;
+                let elem = match fill {
+                    Some(v) => self.check_exp(v)?,
+                    None => {
+                        // Zero is only a valid int or bool.
+                        let elem = self.fresh();
+                        self.constrain(&elem, &[Type::Int, Type::Bool])?;
+                        elem
+                    },
+                };
+                Type::Array(Box::new(elem))
+            },
+            Expr::Index(a, i) => {
+                let elem = self.fresh();
+                self.expect_exp(&Type::Array(Box::new(elem.clone())), a)?;
+                self.expect_exp(&Type::Int, i)?;
+                elem
+            },
+            Expr::Len(a) => {
+                let elem = self.fresh();
+                self.expect_exp(&Type::Array(Box::new(elem)), a)?;
+                Type::Int
+            },
+            Expr::Record(r, inits) => {
+                for (x, e) in inits {
+                    match self.field_type(r, x) {
+                        Some(t) => self.expect_exp(&t, e)?,
+                        None => { self.check_exp(e)?; },
+                    }
+                }
+                Type::Record(r.clone())
+            },
+            Expr::Field(e, x) => {
+                let t = self.check_exp(e)?;
+                self.check_field(&t, x)?
+            },
+            Expr::And(e1, e2) | Expr::Or(e1, e2) => {
+                self.expect_exp(&Type::Bool, e1)?;
+                self.expect_exp(&Type::Bool, e2)?;
+                Type::Bool
+            },
+            Expr::Not(e) => {
+                self.expect_exp(&Type::Bool, e)?;
+                Type::Bool
+            },
+            Expr::If(c, e1, e2) => {
+                self.expect_exp(&Type::Bool, c)?;
+                let t = self.check_exp(e1)?;
+                self.expect_exp(&t, e2)?;
+                t
+            },
+        };
+        Ok(t)
+    }
+
+    fn check_call(&mut self, f: &Var, args: &[Expr]) -> Result<Type, TypeError> {
+        let (params, ret) = match self.procs.get(f) {
+            Some(sig) => sig.clone(),
+            None => (Vec::new(), self.fresh()),
+        };
+        for (i, arg) in args.iter().enumerate() {
+            match params.get(i) {
+                Some(param) => self.expect_exp(param, arg)?,
+                None => { self.check_exp(arg)?; },
+            }
+        }
+        Ok(ret)
+    }
+
+    fn check_builtin(&mut self, b: Builtin, args: &[Expr]) -> Result<Type, TypeError> {
+        let t = match (b, args) {
+            (Builtin::Argc, []) => Type::Int,
+            (Builtin::Eof, []) => Type::Bool,
+            (Builtin::Abs, [e]) => {
+                let t = self.check_exp(e)?;
+                self.constrain(&t, &[Type::Int, Type::Float])?;
+                t
+            },
+            (Builtin::Min | Builtin::Max | Builtin::Pow, [e1, e2]) => self.check_numeric(e1, e2)?,
+            // The compiler reports the wrong number of arguments.
+            _ => {
+                for arg in args {
+                    self.check_exp(arg)?;
+                }
+                self.fresh()
+            },
+        };
+        Ok(t)
+    }
+
+    fn check_stmt(&mut self, s: &Stmt) -> Result<(), TypeError> {
+        match s {
+            Stmt::Let(x, e) => {
+                let t = self.check_exp(e)?;
+                self.declare(x, t);
+            },
+            Stmt::Assign(x, e) => {
+                let t = self.lookup(x);
+                self.expect_exp(&t, e)?;
+            },
+            Stmt::Read(x) => {
+                let t = self.lookup(x);
+                self.expect(&Type::Int, &t)?;
+            },
+            Stmt::Store(a, i, e) => {
+                let elem = self.fresh();
+                let t = self.lookup(a);
+                self.expect(&Type::Array(Box::new(elem.clone())), &t)?;
+                self.expect_exp(&Type::Int, i)?;
+                self.expect_exp(&elem, e)?;
+            },
+            Stmt::SetField(r, x, e) => {
+                let t = self.lookup(r);
+                let field = self.check_field(&t, x)?;
+                self.expect_exp(&field, e)?;
+            },
+            Stmt::If(e, bt, bf) => {
+                self.expect_exp(&Type::Bool, e)?;
+                self.check_block(bt)?;
+                self.check_block(bf)?;
+            },
+            Stmt::While(e, b) => {
+                self.expect_exp(&Type::Bool, e)?;
+                self.check_block(b)?;
+            },
+            Stmt::For(x, lo, hi, step, b) => {
+                self.expect_exp(&Type::Int, lo)?;
+                self.expect_exp(&Type::Int, hi)?;
+                if let Some(step) = step {
+                    self.expect_exp(&Type::Int, step)?;
+                }
+                self.scopes.push(HashMap::new());
+                self.declare(x, Type::Int);
+                self.check_block(b)?;
+                self.scopes.pop();
+            },
+            Stmt::Print(items, _newline) => {
+                for item in items {
+                    if let PrintItem::Expr(e) = item {
+                        self.check_exp(e)?;
+                    }
+                }
+            },
+            Stmt::Call(f, args) => {
+                self.check_call(f, args)?;
+            },
+            Stmt::Return(e) => {
+                let t = self.check_exp(e)?;
+                if let Some(ret) = self.ret.clone() {
+                    self.expect(&ret, &t)?;
+                }
+            },
+            Stmt::Break | Stmt::Continue => {},
+            Stmt::Match(e, cases) => {
+                self.expect_exp(&Type::Int, e)?;
+                for (_, body) in cases {
+                    self.check_block(body)?;
+                }
+            },
+            Stmt::Exit(e) => self.expect_exp(&Type::Int, e)?,
+            Stmt::Assert(e, _span) => self.expect_exp(&Type::Bool, e)?,
+        }
+        Ok(())
+    }
+
+    fn check_block(&mut self, b: &Block) -> Result<(), TypeError> {
+        let outer = self.span;
+        self.scopes.push(HashMap::new());
+        for (span, s) in &b.0 {
+            self.span = *span;
+            self.check_stmt(s)?;
+        }
+        self.scopes.pop();
+        self.span = outer;
+        Ok(())
+    }
+}
+
+// Whether running a block may go on to whatever follows it, rather than always returning or
+// exiting. Loops are assumed to end, even if their condition is always true.
+fn can_complete(b: &Block) -> bool {
+    b.0.iter().all(|(_, s)| match s {
+        Stmt::Return(_) | Stmt::Exit(_) => false,
+        Stmt::If(_, bt, bf) => can_complete(bt) || can_complete(bf),
+        Stmt::Match(_, cases) => cases.iter().any(|(_, body)| can_complete(body)),
+        _ => true,
+    })
+}
+
+pub fn check_program(p: &Program) -> Result<(), TypeError> {
+    let mut checker = Checker::new();
+
+    for r in &p.records {
+        let fields = r.fields.iter().map(|x| (x.clone(), checker.fresh())).collect();
+        checker.records.push((r.name.clone(), fields));
+    }
+
+    for c in &p.consts {
+        checker.span = c.span;
+        let t = checker.check_exp(&c.value)?;
+        checker.consts.insert(c.name.clone(), t);
+    }
+
+    for proc in &p.procs {
+        let params = proc.params.iter().map(|_| checker.fresh()).collect();
+        let ret = checker.fresh();
+        checker.procs.insert(proc.name.clone(), (params, ret));
+    }
+    for proc in &p.procs {
+        let (params, ret) = checker.procs[&proc.name].clone();
+        checker.scopes.push(proc.params.iter().cloned().zip(params).collect());
+        checker.ret = Some(ret.clone());
+        checker.check_block(&proc.body)?;
+        checker.scopes.pop();
+        // Falling off the end of a procedure returns 0, which is only a valid int or bool (false).
+        if can_complete(&proc.body) {
+            checker.span = proc.span;
+            checker.constrain(&ret, &[Type::Int, Type::Bool])?;
+        }
+    }
+
+    checker.ret = None;
+    checker.check_block(&p.body)?;
+    checker.check_pending_fields()?;
+
+    // Some types may only have become known after they were constrained.
+    for i in 0..checker.constraints.len() {
+        checker.check_constraint(i)?;
+    }
+    Ok(())
+}
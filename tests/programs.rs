@@ -0,0 +1,112 @@
+// Runs every program in 'tests/programs', and compares what it prints with the '.out' file of
+// the same name. The first line of a program may give the options and arguments to run it with,
+// as '# args: ...', and its input is taken from the '.in' file of the same name, if there is one.
+//
+// Only the results are compared, not the bytecode, followed by anything printed to stderr and
+// the exit status, unless it is 0. Set BLESS=1 to write the '.out' files instead. Programs are
+// run from the root of the crate, with relative paths, so that the paths in error messages are the
+// same wherever it is. Files that they import are kept in subdirectories, which are not run.
+//
+// The programs in 'tests/optimize' are run before and after optimization instead, with the
+// argument 3, and both runs must give the same results.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+fn args(src: &str) -> Vec<String> {
+    let line = src.lines().next().unwrap_or("");
+    match line.strip_prefix("# args:") {
+        Some(args) => args.split_whitespace().map(String::from).collect(),
+        None => Vec::new(),
+    }
+}
+
+// The output of the program, without the bytecode or the state of the VM.
+fn output(args: &[String], input: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_project"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("the interpreter should start");
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let result = child.wait_with_output().unwrap();
+
+    let stdout = String::from_utf8(result.stdout).unwrap();
+    let mut lines: Vec<&str> = stdout.lines()
+        .filter(|line| {
+            let dump = ["--- Compiled bytecode: ---", "[", "strings = ", "tables = ", "pc = ", "stack = "];
+            !dump.iter().any(|prefix| line.starts_with(prefix))
+        })
+        .collect();
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    lines.extend(stderr.lines());
+    let status = result.status.code().expect("the interpreter should exit normally");
+    let status = format!("exit status: {}", status);
+    if result.status.code() != Some(0) {
+        lines.push(&status);
+    }
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+// The paths of the programs in 'dir', relative to the root of the crate.
+fn programs(dir: &str) -> Vec<PathBuf> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let dir = Path::new("tests").join(dir);
+    let mut paths: Vec<PathBuf> = fs::read_dir(root.join(&dir)).unwrap()
+        .map(|entry| dir.join(entry.unwrap().file_name()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "prog"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+// Run each program in 'dir', with 'mode' before the path if it is given, and report every one
+// whose output differs from the expected output.
+fn check(dir: &str, mode: Option<&str>) {
+    let bless = std::env::var_os("BLESS").is_some();
+    let mut failures = Vec::new();
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    for path in programs(dir) {
+        let src = fs::read_to_string(root.join(&path)).unwrap();
+        let mut args = args(&src);
+        let at = args.iter().position(|a| !a.starts_with("--")).unwrap_or(args.len());
+        let mut path_args: Vec<String> = mode.into_iter().map(String::from).collect();
+        path_args.push(path.display().to_string());
+        args.splice(at..at, path_args);
+        let input = fs::read_to_string(root.join(path.with_extension("in"))).unwrap_or_default();
+
+        let found = output(&args, &input);
+        if let Some((original, optimized)) = found.split_once("\nOptimized program:\n") {
+            let original = original.strip_prefix("Original program:\n").unwrap_or(original);
+            if original != optimized {
+                failures.push(format!("{}: optimizing changes the results:\n{}", path.display(), found));
+                continue;
+            }
+        }
+        let expected_path = root.join(path.with_extension("out"));
+        if bless {
+            fs::write(&expected_path, &found).unwrap();
+            continue;
+        }
+        let expected = fs::read_to_string(&expected_path).unwrap_or_default();
+        if found != expected {
+            failures.push(format!("{}:\n--- expected ---\n{}--- found ---\n{}", path.display(), expected, found));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn programs_run() {
+    check("programs", None);
+}
+
+#[test]
+fn programs_optimize() {
+    check("optimize", Some("TEST"));
+}